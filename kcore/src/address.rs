@@ -0,0 +1,302 @@
+use core::fmt::{self, Debug, Formatter};
+
+pub const PAGE_SIZE_BITS: usize = 0xc;
+pub const PAGE_SIZE: usize = 1 << PAGE_SIZE_BITS;   // 4k
+const PA_WIDTH_SV39: usize = 56;
+const VA_WIDTH_SV39: usize = 39;
+const PPN_WIDTH_SV39: usize = PA_WIDTH_SV39 - PAGE_SIZE_BITS;
+const VPN_WIDTH_SV39: usize = VA_WIDTH_SV39 - PAGE_SIZE_BITS;
+
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
+pub struct PhysAddr(pub usize);
+
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
+pub struct VirtAddr(pub usize);
+
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
+pub struct PhysPageNum(pub usize);
+
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
+pub struct VirtPageNum(pub usize);
+
+
+impl Debug for VirtAddr {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    f.write_fmt(format_args!("VA:{:#x}", self.0))
+  }
+}
+
+impl Debug for VirtPageNum {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    f.write_fmt(format_args!("VPN:{:#x}", self.0))
+  }
+}
+
+impl Debug for PhysAddr {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    f.write_fmt(format_args!("PA:{:#x}", self.0))
+  }
+}
+
+impl Debug for PhysPageNum {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    f.write_fmt(format_args!("PPN:{:#x}", self.0))
+  }
+}
+
+impl From<usize> for PhysAddr {
+  fn from(value: usize) -> Self {
+    Self(value & ((1 << PA_WIDTH_SV39) - 1))
+  }
+}
+
+impl From<usize> for PhysPageNum {
+  fn from(value: usize) -> Self {
+    Self(value & ((1 << PPN_WIDTH_SV39) - 1))
+  }
+}
+
+impl From<usize> for VirtAddr {
+  fn from(value: usize) -> Self {
+    Self(value & ((1 << VA_WIDTH_SV39) - 1))
+  }
+}
+
+impl From<usize> for VirtPageNum {
+  fn from(value: usize) -> Self {
+    Self(value & ((1 << VPN_WIDTH_SV39) - 1))
+  }
+}
+
+impl From<PhysAddr> for usize {
+  fn from(value: PhysAddr) -> Self {
+    value.0
+  }
+}
+
+impl From<PhysPageNum> for usize {
+  fn from(value: PhysPageNum) -> Self {
+    value.0
+  }
+}
+
+impl From<VirtAddr> for usize {
+  fn from(value: VirtAddr) -> Self {
+    if value.0 >= (1 << (VA_WIDTH_SV39 - 1)) {
+      value.0 | (!((1 << VA_WIDTH_SV39) - 1))
+    } else {
+      value.0
+    }
+  }
+}
+
+impl From<VirtPageNum> for usize {
+  fn from(value: VirtPageNum) -> Self {
+    value.0
+  }
+}
+
+impl PhysAddr {
+  pub fn page_offset(&self) -> usize {
+    self.0 & (PAGE_SIZE - 1)
+  }
+
+  pub fn aligned(&self) -> bool {
+    self.page_offset() == 0
+  }
+
+  pub fn floor(&self) -> PhysPageNum {
+    PhysPageNum(self.0 / PAGE_SIZE)
+  }
+
+  pub fn ceil(&self) -> PhysPageNum {
+    PhysPageNum((self.0 - 1 + PAGE_SIZE) / PAGE_SIZE)
+  }
+}
+
+impl From<PhysAddr> for PhysPageNum {
+  fn from(value: PhysAddr) -> Self {
+    assert_eq!(value.page_offset(), 0);
+    value.floor()
+  }
+}
+
+impl From<PhysPageNum> for PhysAddr {
+  fn from(value: PhysPageNum) -> Self {
+    Self(value.0 << PAGE_SIZE_BITS)
+  }
+}
+
+impl VirtAddr {
+  pub fn page_offset(&self) -> usize {
+    self.0 & (PAGE_SIZE - 1)
+  }
+
+  pub fn aligned(&self) -> bool {
+    self.page_offset() == 0
+  }
+
+  pub fn floor(&self) -> VirtPageNum {
+    VirtPageNum(self.0 / PAGE_SIZE)
+  }
+
+  pub fn ceil(&self) -> VirtPageNum {
+    VirtPageNum((self.0 - 1 + PAGE_SIZE) / PAGE_SIZE)
+  }
+}
+
+impl From<VirtAddr> for VirtPageNum {
+  fn from(value: VirtAddr) -> Self {
+    assert_eq!(value.page_offset(), 0);
+    value.floor()
+  }
+}
+
+impl From<VirtPageNum> for VirtAddr {
+  fn from(value: VirtPageNum) -> Self {
+    Self(value.0 << PAGE_SIZE_BITS)
+  }
+}
+
+impl VirtPageNum {
+  pub fn indexes(&self) -> [usize; 3] {
+    let mut vpn = self.0;
+    let mut idx = [0usize; 3];
+    idx.iter_mut().rev()
+      .for_each(|x| {
+        *x = vpn & 511;
+        vpn >>= 9;
+      });
+    idx
+  }
+}
+
+pub trait StepByOne {
+  fn step(&mut self);
+}
+
+impl StepByOne for VirtPageNum {
+  fn step(&mut self) {
+    self.0 += 1;
+  }
+}
+
+#[derive(Copy, Clone)]
+pub struct SimpleRange<T>
+  where T: StepByOne + Copy + PartialEq + PartialOrd + Debug
+{
+  l: T,
+  r: T,
+}
+
+impl<T> SimpleRange<T>
+  where T: StepByOne + Copy + PartialEq + PartialOrd + Debug
+{
+  pub fn new(start: T, end: T) -> Self {
+    assert!(start <= end, "start {:?} > end {:?}!", start, end);
+    Self { l: start, r: end }
+  }
+
+  pub fn get_start(&self) -> T {
+    self.l
+  }
+
+  pub fn get_end(&self) -> T {
+    self.r
+  }
+}
+
+impl<T> IntoIterator for SimpleRange<T>
+  where T: StepByOne + Copy + PartialEq + PartialOrd + Debug
+{
+  type Item = T;
+  type IntoIter = SimpleRangeIterator<T>;
+
+  fn into_iter(self) -> Self::IntoIter {
+    SimpleRangeIterator::new(self.l, self.r)
+  }
+}
+
+pub struct SimpleRangeIterator<T>
+  where T: StepByOne + Copy + PartialEq + PartialOrd + Debug
+{
+  current: T,
+  end: T,
+}
+
+impl<T> SimpleRangeIterator<T>
+  where T: StepByOne + Copy + PartialEq + PartialOrd + Debug
+{
+  pub fn new(l: T, r: T) -> Self {
+    Self { current: l, end: r }
+  }
+}
+
+impl<T> Iterator for SimpleRangeIterator<T>
+  where T: StepByOne + Copy + PartialEq + PartialOrd + Debug
+{
+  type Item = T;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.current == self.end {
+      None
+    } else {
+      let ret = self.current;
+      self.current.step();
+      Some(ret)
+    }
+  }
+}
+
+pub type VPNRange = SimpleRange<VirtPageNum>;
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn virt_addr_sign_extends() {
+    let va = VirtAddr::from(0xffff_ffff_ffff_f000);
+    assert_eq!(va.0, (1 << 39) - PAGE_SIZE);
+    assert_eq!(usize::from(va), 0xffff_ffff_ffff_f000);
+    assert_eq!(usize::from(VirtAddr::from(0x1000)), 0x1000);
+  }
+
+  #[test]
+  fn floor_and_ceil() {
+    let va = VirtAddr::from(0x1234);
+    assert_eq!(va.page_offset(), 0x234);
+    assert_eq!(va.floor(), VirtPageNum(1));
+    assert_eq!(va.ceil(), VirtPageNum(2));
+    assert_eq!(VirtAddr::from(0x2000).ceil(), VirtPageNum(2));
+    assert_eq!(PhysAddr::from(0x8020_0000).floor(), PhysPageNum(0x80200));
+    assert_eq!(PhysAddr::from(PhysPageNum(0x80200)), PhysAddr(0x8020_0000));
+  }
+
+  #[test]
+  #[should_panic]
+  fn unaligned_addr_to_page_number() {
+    let _ = VirtPageNum::from(VirtAddr::from(0x1001));
+  }
+
+  #[test]
+  fn indexes_of_levels() {
+    let vpn = VirtPageNum((3 << 18) | (5 << 9) | 7);
+    assert_eq!(vpn.indexes(), [3, 5, 7]);
+    assert_eq!(VirtAddr::from(0xffff_ffff_ffff_f000).floor().indexes(), [511, 511, 511]);
+  }
+
+  #[test]
+  fn range_iterates_half_open() {
+    let range = VPNRange::new(VirtPageNum(2), VirtPageNum(5));
+    let vpns: Vec<_> = range.into_iter().collect();
+    assert_eq!(vpns, [VirtPageNum(2), VirtPageNum(3), VirtPageNum(4)]);
+    assert_eq!(VPNRange::new(VirtPageNum(4), VirtPageNum(4)).into_iter().count(), 0);
+  }
+
+  #[test]
+  #[should_panic]
+  fn range_rejects_reversed_bounds() {
+    VPNRange::new(VirtPageNum(5), VirtPageNum(2));
+  }
+}
@@ -0,0 +1,131 @@
+use crate::address::PhysPageNum;
+use crate::index_allocator::IndexAllocator;
+
+pub trait FrameAllocator {
+  fn new() -> Self;
+  fn alloc(&mut self) -> Option<PhysPageNum>;
+  fn dealloc(&mut self, ppn: PhysPageNum);
+}
+
+/// Frames handed out by an [`IndexAllocator`] of physical page numbers.
+pub struct StackFrameAllocator {
+  frames: IndexAllocator,
+}
+
+impl StackFrameAllocator {
+  pub fn init(&mut self, l: PhysPageNum, r: PhysPageNum) {
+    self.frames.init(l.0, r.0);
+  }
+
+  /// Allocate `pages` contiguous frames aligned to `align` pages, see
+  /// [`IndexAllocator::alloc_contiguous`].
+  pub fn alloc_contiguous(&mut self, pages: usize, align: usize) -> Option<PhysPageNum> {
+    self.frames.alloc_contiguous(pages, align).map(PhysPageNum)
+  }
+
+  pub fn dealloc_contiguous(&mut self, start: PhysPageNum, pages: usize) {
+    self.frames.dealloc_contiguous(start.0, pages);
+  }
+}
+
+impl FrameAllocator for StackFrameAllocator {
+  fn new() -> Self {
+    Self {
+      frames: IndexAllocator::default(),
+    }
+  }
+
+  fn alloc(&mut self) -> Option<PhysPageNum> {
+    self.frames.alloc().map(PhysPageNum)
+  }
+
+  fn dealloc(&mut self, ppn: PhysPageNum) {
+    self.frames.dealloc(ppn.0);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec::Vec;
+  use super::*;
+
+  fn allocator(l: usize, r: usize) -> StackFrameAllocator {
+    let mut allocator = StackFrameAllocator::new();
+    allocator.init(PhysPageNum(l), PhysPageNum(r));
+    allocator
+  }
+
+  #[test]
+  fn alloc_until_exhausted() {
+    let mut allocator = allocator(0x100, 0x103);
+    let frames: Vec<_> = (0..3).map(|_| allocator.alloc().unwrap()).collect();
+    assert_eq!(frames, [PhysPageNum(0x100), PhysPageNum(0x101), PhysPageNum(0x102)]);
+    assert!(allocator.alloc().is_none());
+  }
+
+  #[test]
+  fn recycled_frames_are_reused_first() {
+    let mut allocator = allocator(0x100, 0x110);
+    let first = allocator.alloc().unwrap();
+    let second = allocator.alloc().unwrap();
+    allocator.dealloc(first);
+    assert_eq!(allocator.alloc(), Some(first));
+    assert_eq!(allocator.alloc(), Some(PhysPageNum(second.0 + 1)));
+  }
+
+  #[test]
+  #[should_panic]
+  fn double_free_panics() {
+    let mut allocator = allocator(0x100, 0x110);
+    let frame = allocator.alloc().unwrap();
+    allocator.dealloc(frame);
+    allocator.dealloc(frame);
+  }
+
+  #[test]
+  #[should_panic]
+  fn free_of_never_allocated_panics() {
+    let mut allocator = allocator(0x100, 0x110);
+    allocator.dealloc(PhysPageNum(0x105));
+  }
+
+  #[test]
+  fn contiguous_alloc_aligns_and_recycles_gap() {
+    let mut allocator = allocator(0x101, 0x200);
+    let start = allocator.alloc_contiguous(4, 4).unwrap();
+    assert_eq!(start, PhysPageNum(0x104));
+    // frames skipped for alignment
+    let mut gap: Vec<_> = (0..3).map(|_| allocator.alloc().unwrap().0).collect();
+    gap.sort();
+    assert_eq!(gap, [0x101, 0x102, 0x103]);
+    assert_eq!(allocator.alloc(), Some(PhysPageNum(0x108)));
+    assert!(allocator.alloc_contiguous(0x100, 1).is_none());
+  }
+
+  #[test]
+  fn contiguous_dealloc_merges_at_end() {
+    let mut allocator = allocator(0x100, 0x200);
+    let start = allocator.alloc_contiguous(8, 1).unwrap();
+    allocator.dealloc_contiguous(start, 8);
+    assert_eq!(allocator.alloc_contiguous(8, 1), Some(start));
+    allocator.alloc().unwrap();
+    allocator.dealloc_contiguous(start, 8);
+    let reused: Vec<_> = (0..8).map(|_| allocator.alloc().unwrap()).collect();
+    assert!(reused.iter().all(|ppn| (start.0..start.0 + 8).contains(&ppn.0)));
+  }
+
+  #[test]
+  fn contiguous_alloc_reuses_recycled_run() {
+    let mut allocator = allocator(0x100, 0x110);
+    let frames: Vec<_> = (0..0x10).map(|_| allocator.alloc().unwrap()).collect();
+    assert!(allocator.alloc_contiguous(4, 4).is_none());
+    // 0x103..0x10a is free, 0x104 is the only aligned start fitting 4 frames
+    frames[3..10].iter().rev().for_each(|&ppn| allocator.dealloc(ppn));
+    assert_eq!(allocator.alloc_contiguous(4, 4), Some(PhysPageNum(0x104)));
+    assert!(allocator.alloc_contiguous(4, 4).is_none());
+    let mut rest: Vec<_> = (0..3).map(|_| allocator.alloc().unwrap().0).collect();
+    rest.sort();
+    assert_eq!(rest, [0x103, 0x108, 0x109]);
+    assert!(allocator.alloc().is_none());
+  }
+}
@@ -0,0 +1,173 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Hands out indices in `[start, end)` such as frames or swap slots,
+/// recycled ones first. A bitmap of recycled indices keeps the double free
+/// check constant time.
+#[derive(Default)]
+pub struct IndexAllocator {
+  start: usize,
+  current: usize,
+  end: usize,
+  recycled: Vec<usize>,
+  // one bit per index, set while the index is in `recycled`
+  free: Vec<u64>,
+}
+
+impl IndexAllocator {
+  pub fn new(start: usize, end: usize) -> Self {
+    let mut allocator = Self::default();
+    allocator.init(start, end);
+    allocator
+  }
+
+  pub fn init(&mut self, start: usize, end: usize) {
+    self.start = start;
+    self.current = start;
+    self.end = end;
+    // heap may be growing when indices are recycled, never reallocate
+    self.recycled = Vec::with_capacity(end - start);
+    self.free = vec![0; (end - start).div_ceil(64)];
+  }
+
+  fn is_free(&self, index: usize) -> bool {
+    let bit = index - self.start;
+    self.free[bit / 64] & (1 << (bit % 64)) != 0
+  }
+
+  fn set_free(&mut self, index: usize, free: bool) {
+    let bit = index - self.start;
+    if free {
+      self.free[bit / 64] |= 1 << (bit % 64);
+    } else {
+      self.free[bit / 64] &= !(1 << (bit % 64));
+    }
+  }
+
+  pub fn alloc(&mut self) -> Option<usize> {
+    if let Some(index) = self.recycled.pop() {
+      self.set_free(index, false);
+      Some(index)
+    } else if self.current == self.end {
+      None
+    } else {
+      self.current += 1;
+      Some(self.current - 1)
+    }
+  }
+
+  pub fn dealloc(&mut self, index: usize) {
+    if index < self.start || index >= self.current || self.is_free(index) {
+      panic!("Index {:#x} has not been allocated!", index);
+    }
+    self.set_free(index, true);
+    self.recycled.push(index);
+  }
+
+  /// Allocate `count` contiguous indices starting at a multiple of `align`
+  /// from a run of recycled indices, or else from indices never allocated,
+  /// indices skipped for alignment are recycled.
+  pub fn alloc_contiguous(&mut self, count: usize, align: usize) -> Option<usize> {
+    if let Some(start) = self.take_recycled_run(count, align) {
+      return Some(start);
+    }
+    let start = self.current.next_multiple_of(align);
+    if start + count > self.end {
+      return None;
+    }
+    for index in self.current..start {
+      self.set_free(index, true);
+      self.recycled.push(index);
+    }
+    self.current = start + count;
+    Some(start)
+  }
+
+  /// Remove a run of `count` recycled indices starting at a multiple of
+  /// `align`, found in one pass over the bitmap.
+  fn take_recycled_run(&mut self, count: usize, align: usize) -> Option<usize> {
+    if count == 0 || self.recycled.len() < count {
+      return None;
+    }
+    let mut run = self.start.next_multiple_of(align);
+    let mut index = run;
+    while index < run + count {
+      if run + count > self.current {
+        return None;
+      }
+      if self.is_free(index) {
+        index += 1;
+      } else {
+        run = (index + 1).next_multiple_of(align);
+        index = run;
+      }
+    }
+    (run..run + count).for_each(|index| self.set_free(index, false));
+    self.recycled.retain(|index| !(run..run + count).contains(index));
+    Some(run)
+  }
+
+  pub fn dealloc_contiguous(&mut self, start: usize, count: usize) {
+    if start + count == self.current {
+      // merge back into indices never allocated
+      self.current = start;
+    } else {
+      (start..start + count).for_each(|index| self.dealloc(index));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn alloc_until_exhausted() {
+    let mut allocator = IndexAllocator::new(0x40, 0x43);
+    assert_eq!((0..3).map(|_| allocator.alloc().unwrap()).collect::<Vec<_>>(), [0x40, 0x41, 0x42]);
+    assert!(allocator.alloc().is_none());
+  }
+
+  #[test]
+  fn recycled_index_is_reused() {
+    let mut allocator = IndexAllocator::new(0, 0x100);
+    let first = allocator.alloc().unwrap();
+    allocator.alloc().unwrap();
+    allocator.dealloc(first);
+    assert_eq!(allocator.alloc(), Some(first));
+    assert_eq!(allocator.alloc(), Some(2));
+    // freeing again after reuse is fine
+    allocator.dealloc(first);
+  }
+
+  #[test]
+  #[should_panic]
+  fn double_free_panics() {
+    let mut allocator = IndexAllocator::new(0, 0x100);
+    let index = allocator.alloc().unwrap();
+    allocator.dealloc(index);
+    allocator.dealloc(index);
+  }
+
+  #[test]
+  fn contiguous_alloc_finds_aligned_run() {
+    let mut allocator = IndexAllocator::new(0, 0x20);
+    let indices: Vec<_> = (0..0x20).map(|_| allocator.alloc().unwrap()).collect();
+    // 0x5..0x9 and 0xb..0x11 are free, only the second fits 4 at a multiple of 4
+    indices[0x5..0x9].iter().for_each(|&index| allocator.dealloc(index));
+    indices[0xb..0x11].iter().rev().for_each(|&index| allocator.dealloc(index));
+    assert_eq!(allocator.alloc_contiguous(4, 4), Some(0xc));
+    assert!(allocator.alloc_contiguous(4, 4).is_none());
+    assert_eq!(allocator.alloc_contiguous(3, 1), Some(0x5));
+    allocator.dealloc_contiguous(0xc, 4);
+    assert_eq!(allocator.alloc_contiguous(4, 4), Some(0xc));
+  }
+
+  #[test]
+  #[should_panic]
+  fn free_below_start_panics() {
+    let mut allocator = IndexAllocator::new(0x10, 0x100);
+    allocator.alloc().unwrap();
+    allocator.dealloc(0x8);
+  }
+}
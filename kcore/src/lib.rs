@@ -0,0 +1,15 @@
+//! Logic of the kernel that does not depend on the machine, shared by `os`
+//! and host unit tests (`cargo test` in this directory). Physical memory is
+//! reached through [`phys_mem::PhysMem`], identity mapped in kernel and an
+//! array on host.
+
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+pub mod address;
+pub mod frame_allocator;
+pub mod index_allocator;
+pub mod page_table;
+pub mod phys_mem;
+pub mod pid;
@@ -0,0 +1,348 @@
+use bitflags::*;
+use crate::address::{PhysAddr, PhysPageNum, VirtAddr, VirtPageNum};
+use crate::phys_mem::PhysMem;
+
+pub const PTE_FLAGS_BITS: usize = 0xa;
+
+bitflags! {
+  pub struct PTEFlags: u16 {
+    const V = 1 << 0;
+    const R = 1 << 1;
+    const W = 1 << 2;
+    const X = 1 << 3;
+    const U = 1 << 4;
+    const G = 1 << 5;
+    const A = 1 << 6;
+    const D = 1 << 7;
+    const C = 1 << 8;
+    // Page is swapped out, only used when V is cleared
+    const S = 1 << 9;
+  }
+}
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct PageTableEntry {
+  pub bits: usize,
+}
+
+impl PageTableEntry {
+  pub fn new(ppn: PhysPageNum, flags: PTEFlags) -> Self {
+    Self {
+      bits: ppn.0 << PTE_FLAGS_BITS | flags.bits as usize,
+    }
+  }
+
+  pub fn empty() -> Self {
+    Self { bits: 0 }
+  }
+
+  /// Invalid PTE keeping swap `slot` in PPN field and permission in flags.
+  pub fn new_swapped(slot: usize, flags: PTEFlags) -> Self {
+    let flags = (flags - PTEFlags::V - PTEFlags::A - PTEFlags::D) | PTEFlags::S;
+    Self {
+      bits: slot << PTE_FLAGS_BITS | flags.bits as usize,
+    }
+  }
+
+  pub fn ppn(&self) -> PhysPageNum {
+    ((self.bits >> PTE_FLAGS_BITS) & ((1usize << 44) - 1)).into()
+  }
+
+  pub fn flags(&self) -> PTEFlags {
+    PTEFlags::from_bits((self.bits & ((1 << 10) - 1)) as u16).unwrap()
+  }
+
+  pub fn is_valid(&self) -> bool {
+    (self.flags() & PTEFlags::V) != PTEFlags::empty()
+  }
+
+  pub fn is_readable(&self) -> bool {
+    (self.flags() & PTEFlags::R) != PTEFlags::empty()
+  }
+
+  pub fn is_writable(&self) -> bool {
+    (self.flags() & PTEFlags::W) != PTEFlags::empty()
+  }
+
+  pub fn is_executable(&self) -> bool {
+    (self.flags() & PTEFlags::X) != PTEFlags::empty()
+  }
+
+  /// Leaf entry maps a page, others point to next level page table.
+  pub fn is_leaf(&self) -> bool {
+    (self.flags() & (PTEFlags::R | PTEFlags::W | PTEFlags::X)) != PTEFlags::empty()
+  }
+
+  pub fn is_cow_page(&self) -> bool {
+    (self.flags() & PTEFlags::C) != PTEFlags::empty()
+  }
+
+  pub fn is_swapped(&self) -> bool {
+    !self.is_valid() && (self.flags() & PTEFlags::S) != PTEFlags::empty()
+  }
+
+  pub fn swap_slot(&self) -> usize {
+    self.ppn().0
+  }
+}
+
+/// Number of 4 KiB pages a leaf at `level` maps, level 0 is the root.
+pub const fn level_pages(level: usize) -> usize {
+  1 << (9 * (2 - level))
+}
+
+/// Sv39 page table rooted at `root_ppn`, which does not own any frame.
+/// Frames of intermediate tables come from the caller of [`Self::map`].
+#[derive(Copy, Clone)]
+pub struct RawPageTable<M: PhysMem> {
+  root_ppn: PhysPageNum,
+  mem: M,
+}
+
+impl<M: PhysMem> RawPageTable<M> {
+  pub fn new(root_ppn: PhysPageNum, mem: M) -> Self {
+    Self { root_ppn, mem }
+  }
+
+  pub fn root_ppn(&self) -> PhysPageNum {
+    self.root_ppn
+  }
+
+  pub fn token(&self) -> usize {
+    8usize << 60 | self.root_ppn.0
+  }
+
+  /// PPN of returned entry is the 4 KiB page of `vpn`, even in a huge page.
+  pub fn translate(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
+    self.find_leaf(vpn).map(|(pte, level)| {
+      let offset = vpn.0 & (level_pages(level) - 1);
+      PageTableEntry {
+        bits: pte.bits + (offset << PTE_FLAGS_BITS),
+      }
+    })
+  }
+
+  pub fn translate_va(&self, va: VirtAddr) -> Option<PhysAddr> {
+    self.find_ppn(va.floor()).map(|ppn| {
+      (PhysAddr::from(ppn).0 + va.page_offset()).into()
+    })
+  }
+
+  pub fn find_ppn(&self, vpn: VirtPageNum) -> Option<PhysPageNum> {
+    self.translate(vpn).map(|pte| pte.ppn())
+  }
+
+  /// Map `vpn` to `ppn` by a leaf at `level`, tables missing on the way are
+  /// taken from `alloc`, which must return zeroed frames. Return `None` if
+  /// `alloc` runs out.
+  pub fn map(
+    &self,
+    vpn: VirtPageNum,
+    ppn: PhysPageNum,
+    flags: PTEFlags,
+    level: usize,
+    alloc: impl FnMut() -> Option<PhysPageNum>,
+  ) -> Option<()> {
+    assert!(
+      vpn.0.is_multiple_of(level_pages(level)) && ppn.0.is_multiple_of(level_pages(level)),
+      "map: vpn {:?} and ppn {:?} should align to level {}", vpn, ppn, level,
+    );
+    let pte = self.find_pte_create(vpn, level, alloc)?;
+    assert!(!pte.is_valid(), "vpn {:?} is mapped but should not", vpn);
+    *pte = PageTableEntry::new(ppn, flags | PTEFlags::V);
+    Some(())
+  }
+
+  /// Clear leaf entry of `vpn`, return it if it was valid or swapped.
+  /// Intermediate tables are kept.
+  pub fn unmap(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
+    let pte = self.find_pte(vpn)?;
+    if !pte.is_valid() && !pte.is_swapped() {
+      return None;
+    }
+    let old = *pte;
+    *pte = PageTableEntry::empty();
+    Some(old)
+  }
+
+  /// Entry at `level` on the way to `vpn`, creating tables above it.
+  pub fn find_pte_create(
+    &self,
+    vpn: VirtPageNum,
+    level: usize,
+    mut alloc: impl FnMut() -> Option<PhysPageNum>,
+  ) -> Option<&'static mut PageTableEntry> {
+    let index = vpn.indexes();
+    let mut ppn = self.root_ppn;
+    let mut ret = None;
+    for (i, idx) in index.into_iter().enumerate() {
+      let next_pte = &mut self.mem.get_pte_array(ppn)[idx];
+      if i == level {
+        ret = Some(next_pte);
+        break;
+      }
+      if !next_pte.is_valid() {
+        *next_pte = PageTableEntry::new(alloc()?, PTEFlags::V);
+      }
+      assert!(!next_pte.is_leaf(), "vpn {:?} is inside a huge page", vpn);
+      ppn = next_pte.ppn();
+    }
+    ret
+  }
+
+  /// Find leaf entry of `vpn` and its level, last level entry is returned
+  /// even if it is invalid.
+  pub fn find_leaf(&self, vpn: VirtPageNum) -> Option<(&'static mut PageTableEntry, usize)> {
+    let index = vpn.indexes();
+    let mut ppn = self.root_ppn;
+    let mut ret = None;
+    for (i, idx) in index.into_iter().enumerate() {
+      let next_pte = &mut self.mem.get_pte_array(ppn)[idx];
+      if i == 2 || next_pte.is_valid() && next_pte.is_leaf() {
+        ret = Some((next_pte, i));
+        break;
+      }
+      if !next_pte.is_valid() {
+        return None;
+      }
+      ppn = next_pte.ppn();
+    }
+    ret
+  }
+
+  pub fn find_pte(&self, vpn: VirtPageNum) -> Option<&'static mut PageTableEntry> {
+    self.find_leaf(vpn).map(|(pte, _)| pte)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::frame_allocator::{FrameAllocator, StackFrameAllocator};
+  use crate::phys_mem::SimRam;
+
+  const RAM_BASE: PhysPageNum = PhysPageNum(0x80000);
+  const RAM_PAGES: usize = 1024;
+
+  struct Machine {
+    ram: SimRam,
+    frames: StackFrameAllocator,
+    table: RawPageTable<SimRam>,
+  }
+
+  impl Machine {
+    fn new() -> Self {
+      let ram = SimRam::new(RAM_BASE, RAM_PAGES);
+      let mut frames = StackFrameAllocator::new();
+      frames.init(RAM_BASE, PhysPageNum(RAM_BASE.0 + RAM_PAGES));
+      let root = frames.alloc().unwrap();
+      Self { ram, frames, table: RawPageTable::new(root, ram) }
+    }
+
+    fn alloc(&mut self) -> Option<PhysPageNum> {
+      let ppn = self.frames.alloc()?;
+      self.ram.get_bytes_array(ppn).fill(0);
+      Some(ppn)
+    }
+
+    fn map(&mut self, vpn: usize, ppn: PhysPageNum, level: usize) -> Option<()> {
+      let (ram, frames) = (self.ram, &mut self.frames);
+      let alloc = || frames.alloc().inspect(|&ppn| ram.get_bytes_array(ppn).fill(0));
+      self.table.map(VirtPageNum(vpn), ppn, PTEFlags::R | PTEFlags::W, level, alloc)
+    }
+  }
+
+  #[test]
+  fn map_then_translate() {
+    let mut machine = Machine::new();
+    let frame = machine.alloc().unwrap();
+    machine.ram.get_bytes_array(frame)[0x123] = 42;
+    machine.map(0x12345, frame, 2).unwrap();
+
+    let pte = machine.table.translate(VirtPageNum(0x12345)).unwrap();
+    assert!(pte.is_valid() && pte.is_readable() && pte.is_writable() && !pte.is_executable());
+    assert_eq!(pte.ppn(), frame);
+    let pa = machine.table.translate_va(VirtAddr::from(0x1234_5123)).unwrap();
+    assert_eq!(pa, PhysAddr(PhysAddr::from(frame).0 + 0x123));
+    assert_eq!(*machine.ram.get_mut::<u8>(pa), 42);
+    assert!(machine.table.translate(VirtPageNum(0x12346)).is_none_or(|pte| !pte.is_valid()));
+    assert!(machine.table.translate(VirtPageNum(0x40000)).is_none());
+  }
+
+  #[test]
+  fn map_allocates_tables_once() {
+    let mut machine = Machine::new();
+    let frame = machine.alloc().unwrap();
+    machine.map(0x100, frame, 2).unwrap();
+    let next = machine.alloc().unwrap();
+    // root, frame, then level 1 and level 2 tables
+    assert_eq!(next.0, RAM_BASE.0 + 4);
+    machine.map(0x101, next, 2).unwrap();
+    assert_eq!(machine.alloc().unwrap().0, RAM_BASE.0 + 5);
+  }
+
+  #[test]
+  fn unmap_clears_only_leaf() {
+    let mut machine = Machine::new();
+    let frame = machine.alloc().unwrap();
+    machine.map(0x100, frame, 2).unwrap();
+    let old = machine.table.unmap(VirtPageNum(0x100)).unwrap();
+    assert_eq!(old.ppn(), frame);
+    assert!(machine.table.unmap(VirtPageNum(0x100)).is_none());
+    assert!(!machine.table.translate(VirtPageNum(0x100)).unwrap().is_valid());
+    // tables are kept, mapping again allocates nothing
+    machine.map(0x100, frame, 2).unwrap();
+    assert_eq!(machine.alloc().unwrap().0, RAM_BASE.0 + 4);
+  }
+
+  #[test]
+  fn unmap_returns_swapped_entry() {
+    let mut machine = Machine::new();
+    let frame = machine.alloc().unwrap();
+    machine.map(0x100, frame, 2).unwrap();
+    let pte = machine.table.find_pte(VirtPageNum(0x100)).unwrap();
+    *pte = PageTableEntry::new_swapped(7, pte.flags());
+    let old = machine.table.unmap(VirtPageNum(0x100)).unwrap();
+    assert!(old.is_swapped());
+    assert_eq!(old.swap_slot(), 7);
+    assert_eq!(old.flags() & (PTEFlags::R | PTEFlags::W), PTEFlags::R | PTEFlags::W);
+  }
+
+  #[test]
+  fn huge_page_translates_inner_pages() {
+    let mut machine = Machine::new();
+    let base = PhysPageNum(0x80200);
+    machine.map(0x400, base, 1).unwrap();
+    let (_, level) = machine.table.find_leaf(VirtPageNum(0x5ff)).unwrap();
+    assert_eq!(level, 1);
+    assert_eq!(machine.table.find_ppn(VirtPageNum(0x5ff)), Some(PhysPageNum(0x803ff)));
+    assert_eq!(machine.table.unmap(VirtPageNum(0x450)).unwrap().ppn(), base);
+    assert!(machine.table.find_ppn(VirtPageNum(0x400)).is_none());
+  }
+
+  #[test]
+  #[should_panic]
+  fn map_twice_panics() {
+    let mut machine = Machine::new();
+    let frame = machine.alloc().unwrap();
+    machine.map(0x100, frame, 2).unwrap();
+    machine.map(0x100, frame, 2).unwrap();
+  }
+
+  #[test]
+  #[should_panic]
+  fn map_inside_huge_page_panics() {
+    let mut machine = Machine::new();
+    machine.map(0x400, PhysPageNum(0x80200), 1).unwrap();
+    let frame = machine.alloc().unwrap();
+    machine.map(0x401, frame, 2).unwrap();
+  }
+
+  #[test]
+  fn map_fails_when_frames_run_out() {
+    let mut machine = Machine::new();
+    while machine.alloc().is_some() {}
+    assert!(machine.map(0x100, RAM_BASE, 2).is_none());
+  }
+}
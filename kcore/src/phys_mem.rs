@@ -0,0 +1,69 @@
+//! Backends through which page tables and frames are read and written.
+
+use core::mem::size_of;
+use core::slice;
+use crate::address::{PhysAddr, PhysPageNum, PAGE_SIZE};
+use crate::page_table::PageTableEntry;
+
+/// Physical memory reachable by the kernel, returned references are
+/// `'static` as memory is never given back.
+pub trait PhysMem {
+  /// Pointer through which `pa` is accessed.
+  fn ptr(&self, pa: PhysAddr) -> *mut u8;
+
+  fn get_pte_array(&self, ppn: PhysPageNum) -> &'static mut [PageTableEntry] {
+    let ptr = self.ptr(ppn.into()) as *mut PageTableEntry;
+    unsafe { slice::from_raw_parts_mut(ptr, PAGE_SIZE / size_of::<PageTableEntry>()) }
+  }
+
+  fn get_bytes_array(&self, ppn: PhysPageNum) -> &'static mut [u8] {
+    unsafe { slice::from_raw_parts_mut(self.ptr(ppn.into()), PAGE_SIZE) }
+  }
+
+  fn get_mut<T>(&self, pa: PhysAddr) -> &'static mut T {
+    unsafe { (self.ptr(pa) as *mut T).as_mut().unwrap() }
+  }
+}
+
+/// Physical memory mapped at the same virtual address, as in kernel.
+#[derive(Copy, Clone)]
+pub struct Identity;
+
+impl PhysMem for Identity {
+  fn ptr(&self, pa: PhysAddr) -> *mut u8 {
+    pa.0 as *mut u8
+  }
+}
+
+/// Frames `[base, base + pages)` backed by a leaked array on host.
+#[cfg(test)]
+#[derive(Copy, Clone)]
+pub struct SimRam {
+  base: PhysPageNum,
+  pages: usize,
+  ram: *mut u8,
+}
+
+#[cfg(test)]
+impl SimRam {
+  pub fn new(base: PhysPageNum, pages: usize) -> Self {
+    #[repr(C, align(4096))]
+    struct Page([u8; PAGE_SIZE]);
+    let ram: Vec<Page> = (0..pages).map(|_| Page([0; PAGE_SIZE])).collect();
+    Self {
+      base,
+      pages,
+      ram: Box::leak(ram.into_boxed_slice()).as_mut_ptr() as *mut u8,
+    }
+  }
+}
+
+#[cfg(test)]
+impl PhysMem for SimRam {
+  fn ptr(&self, pa: PhysAddr) -> *mut u8 {
+    let offset = pa.0.checked_sub(PhysAddr::from(self.base).0)
+      .filter(|&offset| offset < self.pages * PAGE_SIZE)
+      .unwrap_or_else(|| panic!("{:?} is outside simulated RAM", pa));
+    unsafe { self.ram.add(offset) }
+  }
+}
@@ -0,0 +1,70 @@
+use alloc::vec::Vec;
+
+#[derive(Default)]
+pub struct PidAllocator {
+  current: usize,
+  recycled: Vec<usize>,
+}
+
+impl PidAllocator {
+  pub fn new() -> Self {
+    Self {
+      current: 0,
+      recycled: Vec::new(),
+    }
+  }
+
+  pub fn alloc(&mut self) -> usize {
+    if let Some(pid) = self.recycled.pop() {
+      pid
+    } else {
+      self.current += 1;
+      self.current - 1
+    }
+  }
+
+  pub fn dealloc(&mut self, pid: usize) {
+    assert!(pid < self.current);
+    assert!(
+      self.recycled.iter().find(|ppid| **ppid == pid).is_none(),
+      "pid {} has been deallocated but should not!", pid
+    );
+    self.recycled.push(pid);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn pids_count_up_from_zero() {
+    let mut allocator = PidAllocator::new();
+    assert_eq!((0..3).map(|_| allocator.alloc()).collect::<Vec<_>>(), [0, 1, 2]);
+  }
+
+  #[test]
+  fn freed_pid_is_reused() {
+    let mut allocator = PidAllocator::new();
+    let pid = allocator.alloc();
+    allocator.alloc();
+    allocator.dealloc(pid);
+    assert_eq!(allocator.alloc(), pid);
+    assert_eq!(allocator.alloc(), 2);
+  }
+
+  #[test]
+  #[should_panic]
+  fn double_free_panics() {
+    let mut allocator = PidAllocator::new();
+    let pid = allocator.alloc();
+    allocator.dealloc(pid);
+    allocator.dealloc(pid);
+  }
+
+  #[test]
+  #[should_panic]
+  fn free_of_never_allocated_panics() {
+    PidAllocator::new().dealloc(0);
+  }
+}
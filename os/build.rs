@@ -1,10 +1,12 @@
-use std::fs::{read_dir, File};
+use std::env;
+use std::fs::{read_dir, read_to_string, File};
 use std::io::{Result, Write};
 
 fn main() {
     println!("cargo:rerun-if-changed=../user/src/");
     println!("cargo:rerun-if-changed={}", TARGET_PATH);
     insert_app_data().unwrap();
+    insert_symbol_table().unwrap();
 }
 
 static TARGET_PATH: &str = "../user/target/riscv64gc-unknown-none-elf/release/";
@@ -65,3 +67,78 @@ app_{0}_end:"#,
     }
     Ok(())
 }
+
+/// Output of `nm` on the kernel, written by `make` after the first link.
+/// The kernel is linked again with the symbols embedded, code addresses
+/// do not change since .text comes before .rodata.
+fn symbols_path() -> String {
+    format!(
+        "target/riscv64gc-unknown-none-elf/{}/kernel.sym",
+        env::var("PROFILE").unwrap()
+    )
+}
+
+fn insert_symbol_table() -> Result<()> {
+    let path = symbols_path();
+    println!("cargo:rerun-if-changed={}", path);
+    // empty table before the first link
+    let listing = read_to_string(&path).unwrap_or_default();
+    let mut symbols: Vec<(usize, String)> = listing
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, ' ');
+            let addr = usize::from_str_radix(fields.next()?, 16).ok()?;
+            let kind = fields.next()?;
+            let name = fields.next()?;
+            matches!(kind, "t" | "T").then(|| (addr, strip_hash(name).to_string()))
+        })
+        .collect();
+    symbols.sort();
+    symbols.dedup_by_key(|(addr, _)| *addr);
+
+    let mut f = File::create("src/ksyms.S").unwrap();
+    writeln!(
+        f,
+        r#"
+    .section .rodata
+    .align 3
+    .global _ksyms_num
+_ksyms_num:
+    .quad {}
+    .global _ksyms_addrs
+_ksyms_addrs:"#,
+        symbols.len()
+    )?;
+    for (addr, _) in symbols.iter() {
+        writeln!(f, "    .quad {:#x}", addr)?;
+    }
+    // name i is [offsets[i], offsets[i + 1]) of names
+    writeln!(f, "    .global _ksyms_offsets\n_ksyms_offsets:")?;
+    let mut offset = 0;
+    writeln!(f, "    .word 0")?;
+    for (_, name) in symbols.iter() {
+        offset += name.len();
+        writeln!(f, "    .word {}", offset)?;
+    }
+    writeln!(f, "    .global _ksyms_names\n_ksyms_names:")?;
+    for (_, name) in symbols.iter() {
+        writeln!(f, "    .ascii \"{}\"", escape(name))?;
+    }
+    Ok(())
+}
+
+/// Drop hash suffix `::h0123456789abcdef` of demangled rust symbols.
+fn strip_hash(name: &str) -> &str {
+    match name.rfind("::h") {
+        Some(pos) if name.len() - pos == 19 => &name[..pos],
+        _ => name,
+    }
+}
+
+/// Escape for `.ascii` in a template of `global_asm!`.
+fn escape(name: &str) -> String {
+    name.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('{', "{{")
+        .replace('}', "}}")
+}
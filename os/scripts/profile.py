@@ -0,0 +1,72 @@
+#!/usr/bin/env python3
+"""Merge and symbolize profiles printed by the kernel sampling profiler.
+
+Usage: profile.py [--kernel ELF] [--user ELF] [--addr2line TOOL] [LOG]
+
+LOG is console output holding `== Begin profile ==` ... `== End profile ==`,
+stdin if omitted. Histograms of every hart are merged and printed by count.
+Kernel samples keep the symbol printed by the kernel unless --kernel is
+given, user samples are symbolized with --user, the profiled program.
+"""
+
+import argparse
+import subprocess
+import sys
+from collections import Counter
+
+
+def parse(lines):
+    samples = Counter()
+    names = {}
+    in_profile = False
+    for line in lines:
+        line = line.strip()
+        if line == "== Begin profile ==":
+            in_profile = True
+            samples.clear()
+            names.clear()
+        elif line == "== End profile ==":
+            in_profile = False
+        elif in_profile and line[:2] in ("S ", "U "):
+            mode, pc, count, *name = line.split()
+            samples[(mode, int(pc, 16))] += int(count)
+            if name:
+                names[(mode, int(pc, 16))] = name[0]
+    return samples, names
+
+
+def symbolize(tool, elf, pcs):
+    if not elf or not pcs:
+        return {}
+    output = subprocess.run(
+        [tool, "-f", "-C", "-e", elf] + [hex(pc) for pc in pcs],
+        check=True, capture_output=True, text=True,
+    ).stdout.splitlines()
+    # function name and location per address
+    return {pc: f"{output[2 * i]} {output[2 * i + 1]}" for i, pc in enumerate(pcs)}
+
+
+def main():
+    parser = argparse.ArgumentParser()
+    parser.add_argument("--kernel", help="kernel ELF")
+    parser.add_argument("--user", help="ELF of the profiled user program")
+    parser.add_argument("--addr2line", default="llvm-addr2line")
+    parser.add_argument("log", nargs="?")
+    args = parser.parse_args()
+
+    with open(args.log) if args.log else sys.stdin as f:
+        samples, names = parse(f)
+    total = sum(samples.values())
+    if total == 0:
+        sys.exit("no samples found")
+    for mode, elf in (("S", args.kernel), ("U", args.user)):
+        pcs = sorted(pc for m, pc in samples if m == mode)
+        for pc, name in symbolize(args.addr2line, elf, pcs).items():
+            names[(mode, pc)] = name
+    for (mode, pc), count in samples.most_common():
+        name = names.get((mode, pc), "")
+        print(f"{100 * count / total:6.2f}% {count:6} {mode} {pc:#018x} {name}")
+
+
+if __name__ == "__main__":
+    main()
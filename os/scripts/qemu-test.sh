@@ -0,0 +1,17 @@
+#!/bin/sh
+# Cargo runner for the test kernel: `cargo test` passes the built ELF as $1.
+# QEMU options come from `make test`, which also builds swap and user apps.
+set -e
+
+if [ -z "$QEMU_BASE" ]; then
+    echo "qemu-test.sh: QEMU_BASE is not set, run tests with \`make test\`" >&2
+    exit 1
+fi
+
+ELF="$1"
+BIN="$ELF.bin"
+rust-objcopy --binary-architecture=riscv64 "$ELF" --strip-all -O binary "$BIN"
+
+# TEST_TIMEOUT in seconds kills a hanging test kernel
+TIMEOUT="${TEST_TIMEOUT:+timeout $TEST_TIMEOUT}"
+exec $TIMEOUT qemu-system-riscv64 $QEMU_BASE -device loader,file="$BIN",addr=0x80200000
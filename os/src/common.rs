@@ -1,6 +1,6 @@
 use core::arch::asm;
+use riscv::register::sstatus;
 use crate::task::current_cpu;
-use crate::trap::{disable_timer_interrupt, enable_timer_interrupt};
 
 pub fn r_tp() -> usize {
   let tp: usize;
@@ -23,17 +23,23 @@ pub fn cpuid() -> usize {
   r_tp()
 }
 
+/// Whether kernel takes interrupts, which are IPIs and, only while
+/// profiling, timer interrupts enabled by `trap_return`.
 pub fn intr_get() -> bool {
   let sstatus = r_sstatus();
   sstatus & (1 << 1) != 0
 }
 
 pub fn intr_off() {
-  disable_timer_interrupt();
+  unsafe {
+    sstatus::clear_sie();
+  }
 }
 
 pub fn intr_on() {
-  enable_timer_interrupt();
+  unsafe {
+    sstatus::set_sie();
+  }
 }
 
 pub fn push_off() {
@@ -1,14 +1,23 @@
 //! Constants used in rCore
 
+// initial size, user stack grows on demand up to USER_STACK_LIMIT
 pub const USER_STACK_SIZE: usize = 1 << 13;
+pub const USER_STACK_LIMIT: usize = 1 << 20;
+// unmapped region below the limit, faults inside are stack overflows
+pub const USER_STACK_GUARD: usize = 1 << 16;
 pub const KERNEL_STACK_SIZE: usize = 1 << 13;
+// stack of each hart in scheduler, a guard page lies below each
+pub const BOOT_STACK_SIZE: usize = 1 << 14;
+// stack of each hart for traps from kernel
+pub const KERNEL_TRAP_STACK_SIZE: usize = 1 << 13;
 pub const CLOCK_FREQ: usize = 12500000;
 
 // mm
+// initial heap in .bss, then grown from frames by chunks of at least
+// KERNEL_HEAP_GROW_SIZE
 pub const KERNEL_HEAP_SIZE: usize = 0x80_0000;
-pub const PAGE_SIZE_BITS: usize = 0xc;
-pub const PAGE_SIZE: usize = 1 << PAGE_SIZE_BITS;   // 4k
-pub const PTE_FLAGS_BITS: usize = 0xa;
+pub const KERNEL_HEAP_GROW_SIZE: usize = 0x10_0000;
+pub use kcore::address::{PAGE_SIZE, PAGE_SIZE_BITS};
 
 pub const TRAMPOLINE: usize = usize::MAX - PAGE_SIZE + 1;
 pub const TRAP_CONTEXT: usize = TRAMPOLINE - PAGE_SIZE;
@@ -16,6 +25,16 @@ pub const TRAP_CONTEXT: usize = TRAMPOLINE - PAGE_SIZE;
 pub const MEMORY_END: usize = 0x88000000;  // 128M
 // pub const MEMORY_END: usize = 0x80800000;     // 8M
 
+// swap
+pub const SWAP_PAGES: usize = 0x4000;  // 64M
+
 // multicore
 // max number of CPUs
 pub const MAX_CPU_NUM: usize = 8;
+
+// device
+pub const MMIO: &[(usize, usize)] = &[
+  (0x1000_1000, 0x1000),  // virtio-blk
+];
+// sifive_test of QEMU virt, mapped only if device tree has it
+pub const VIRT_TEST: usize = 0x10_0000;
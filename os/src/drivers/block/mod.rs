@@ -0,0 +1,20 @@
+mod virtio_blk;
+
+use alloc::sync::Arc;
+use core::any::Any;
+use virtio_blk::VirtIOBlock;
+
+pub const BLOCK_SIZE: usize = 512;
+
+pub trait BlockDevice: Send + Sync + Any {
+  fn read_block(&self, block_id: usize, buf: &mut [u8]);
+  fn write_block(&self, block_id: usize, buf: &[u8]);
+}
+
+/// Probe block device, return `None` if QEMU has no drive attached.
+pub fn block_device() -> Option<Arc<dyn BlockDevice>> {
+  VirtIOBlock::probe().map(|dev| {
+    let dev: Arc<dyn BlockDevice> = Arc::new(dev);
+    dev
+  })
+}
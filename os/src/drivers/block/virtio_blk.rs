@@ -0,0 +1,72 @@
+use virtio_drivers::{VirtIOBlk, VirtIOHeader};
+use crate::drivers::BlockDevice;
+use crate::mm::{
+  frame_alloc_contiguous, frame_dealloc_contiguous, KERNEL_SPACE, PageTable, PhysAddr, PhysPageAccess,
+  PhysPageNum, VirtAddr,
+};
+use crate::sync::SpinMutex;
+
+const VIRTIO0: usize = 0x1000_1000;
+
+pub struct VirtIOBlock(SpinMutex<VirtIOBlk<'static>>);
+
+// Accesses to the device are serialized by the inner lock
+unsafe impl Send for VirtIOBlock {}
+
+unsafe impl Sync for VirtIOBlock {}
+
+impl VirtIOBlock {
+  pub fn probe() -> Option<Self> {
+    unsafe {
+      VirtIOBlk::new(&mut *(VIRTIO0 as *mut VirtIOHeader))
+        .ok()
+        .map(|blk| Self(SpinMutex::new(blk)))
+    }
+  }
+}
+
+impl BlockDevice for VirtIOBlock {
+  fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+    self.0
+      .lock()
+      .read_block(block_id, buf)
+      .expect("Error when reading VirtIOBlk");
+  }
+
+  fn write_block(&self, block_id: usize, buf: &[u8]) {
+    self.0
+      .lock()
+      .write_block(block_id, buf)
+      .expect("Error when writing VirtIOBlk");
+  }
+}
+
+/// Return zero if there are no contiguous frames left.
+#[no_mangle]
+pub extern "C" fn virtio_dma_alloc(pages: usize) -> PhysAddr {
+  match frame_alloc_contiguous(pages, 1) {
+    Some(ppn_base) => {
+      (0..pages).for_each(|i| PhysPageNum(ppn_base.0 + i).get_bytes_array().fill(0));
+      ppn_base.into()
+    }
+    None => PhysAddr(0),
+  }
+}
+
+#[no_mangle]
+pub extern "C" fn virtio_dma_dealloc(pa: PhysAddr, pages: usize) -> i32 {
+  frame_dealloc_contiguous(pa.into(), pages);
+  0
+}
+
+#[no_mangle]
+pub extern "C" fn virtio_phys_to_virt(paddr: PhysAddr) -> VirtAddr {
+  VirtAddr(paddr.0)
+}
+
+#[no_mangle]
+pub extern "C" fn virtio_virt_to_phys(vaddr: VirtAddr) -> PhysAddr {
+  PageTable::from_token(KERNEL_SPACE.lock().token())
+    .translate_va(vaddr)
+    .unwrap()
+}
@@ -0,0 +1,117 @@
+//! Minimal flattened device tree reader, only looks for boot arguments
+//! and the QEMU test device.
+
+use core::{slice, str};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Once;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+
+const MAX_BOOTARGS_LEN: usize = 256;
+
+// copied out of the tree, whose frames may be handed out by frame allocator
+static BOOTARGS: Once<([u8; MAX_BOOTARGS_LEN], usize)> = Once::new();
+static HAS_TEST_DEVICE: AtomicBool = AtomicBool::new(false);
+
+/// What kernel needs from the device tree.
+#[derive(Default)]
+struct DeviceTree {
+  bootargs: Option<&'static [u8]>,
+  has_test_device: bool,
+}
+
+fn read_be32(addr: usize) -> u32 {
+  u32::from_be(unsafe { (addr as *const u32).read_volatile() })
+}
+
+fn align4(addr: usize) -> usize {
+  (addr + 3) & !3
+}
+
+/// Nul terminated string at `addr`.
+fn c_str(addr: usize) -> &'static [u8] {
+  let len = (0..).take_while(|&i| unsafe { *((addr + i) as *const u8) } != 0).count();
+  unsafe { slice::from_raw_parts(addr as *const u8, len) }
+}
+
+/// Whether nul separated `compatible` list names a sifive test device.
+fn is_test_device(compatible: &[u8]) -> bool {
+  compatible.split(|&b| b == 0).any(|name| name.starts_with(b"sifive,test"))
+}
+
+/// Value of `/chosen/bootargs` without the trailing nul and whether a test
+/// device exists, `dtb` must still be identity mapped.
+fn scan(dtb: usize) -> DeviceTree {
+  let mut tree = DeviceTree::default();
+  if dtb == 0 || dtb % 4 != 0 || read_be32(dtb) != FDT_MAGIC {
+    return tree;
+  }
+  let end = dtb + read_be32(dtb + 4) as usize;
+  let strings = dtb + read_be32(dtb + 12) as usize;
+  let mut pos = dtb + read_be32(dtb + 8) as usize;
+  // root node is at depth 1
+  let mut depth = 0;
+  let mut in_chosen = false;
+  while pos < end {
+    let token = read_be32(pos);
+    pos += 4;
+    match token {
+      FDT_BEGIN_NODE => {
+        let name = c_str(pos);
+        pos = align4(pos + name.len() + 1);
+        depth += 1;
+        if depth == 2 {
+          in_chosen = name == b"chosen";
+        }
+      }
+      FDT_END_NODE => depth -= 1,
+      FDT_PROP => {
+        let len = read_be32(pos) as usize;
+        let name = c_str(strings + read_be32(pos + 4) as usize);
+        let value = pos + 8;
+        pos = align4(value + len);
+        let value = unsafe { slice::from_raw_parts(value as *const u8, len) };
+        if in_chosen && depth == 2 && name == b"bootargs" {
+          tree.bootargs = Some(value.strip_suffix(&[0]).unwrap_or(value));
+        } else if name == b"compatible" && is_test_device(value) {
+          tree.has_test_device = true;
+        }
+      }
+      FDT_NOP => {}
+      // FDT_END or a broken tree
+      _ => break,
+    }
+  }
+  tree
+}
+
+/// Copy what kernel needs out of the device tree at `dtb`, must be called
+/// before frames are allocated.
+pub fn init(dtb: usize) {
+  let tree = scan(dtb);
+  HAS_TEST_DEVICE.store(tree.has_test_device, Ordering::Release);
+  BOOTARGS.call_once(|| {
+    let mut buf = [0; MAX_BOOTARGS_LEN];
+    let args = tree.bootargs.unwrap_or_default();
+    let len = args.len().min(MAX_BOOTARGS_LEN);
+    buf[..len].copy_from_slice(&args[..len]);
+    (buf, len)
+  });
+}
+
+/// Kernel command line, empty if the device tree has none.
+pub fn bootargs() -> &'static str {
+  BOOTARGS
+    .get()
+    .and_then(|(buf, len)| str::from_utf8(&buf[..*len]).ok())
+    .unwrap_or("")
+}
+
+/// Whether QEMU test device at `VIRT_TEST` exists, which sets exit status.
+pub fn has_test_device() -> bool {
+  HAS_TEST_DEVICE.load(Ordering::Acquire)
+}
@@ -2,18 +2,117 @@
     .section .text.entry
     .globl _start
 _start:
+    # a0: hartid, a1: dtb
+    # harts beyond MAX_CPU_NUM have no stack, one of them hands boot over
+    li t0, {max_cpu_num}
+    bgeu a0, t0, _hand_over_boot
+    # the first hart boots kernel, others wait to be started
+    la t0, boot_hart_claimed
+    li t1, 1
+    amoswap.w t1, t1, (t0)
+    bnez t1, _park_hart
+    # sp = boot_stack_lower_bound + (hartid + 1) * stride
+    addi t0, a0, 1
+    li t1, {boot_stack_stride}
+    mul t0, t0, t1
     la sp, boot_stack_lower_bound
-    # prepare stack for each core
-    li t0, 4096
-    mul t0, t0, a0
-    add sp, sp, t0
-    li t0, 4096
     add sp, sp, t0
     call rust_main
 
+_hand_over_boot:
+    # start the first hart that has a stack at _start with the same dtb
+    mv s0, a1
+    li s1, 0
+1:
+    li t0, {max_cpu_num}
+    bgeu s1, t0, 3f
+    mv a0, s1
+    la a1, _start
+    mv a2, s0
+    li a6, 0
+    li a7, 0x48534d
+    ecall
+    # started, or already running and racing for boot
+    beqz a0, 2f
+    li t0, -6
+    beq a0, t0, 2f
+    # firmware without HSM starts every hart
+    li t0, -2
+    beq a0, t0, 2f
+    addi s1, s1, 1
+    j 1b
+2:
+    # HSM hart_stop, returns only if it fails
+    li a6, 1
+    li a7, 0x48534d
+    ecall
+4:
+    wfi
+    j 4b
+3:
+    # no hart could boot, report through legacy console and power off
+    la s1, no_boot_hart
+5:
+    lbu a0, 0(s1)
+    beqz a0, 6f
+    li a7, 1
+    ecall
+    addi s1, s1, 1
+    j 5b
+6:
+    # SRST shutdown for system failure, then legacy shutdown
+    li a0, 0
+    li a1, 1
+    li a6, 0
+    li a7, 0x53525354
+    ecall
+    li a7, 8
+    ecall
+    j 4b
+
+    # firmware without HSM starts every hart, wait until released by IPI
+    .globl _park_hart
+_park_hart:
+    # a0: hartid
+    la t0, hart_release
+    slli t1, a0, 3
+    add t0, t0, t1
+    # software interrupt wakes wfi without trapping, as sstatus.SIE is clear
+    li t1, 2
+    csrs sie, t1
+1:
+    ld a1, 0(t0)
+    bnez a1, 2f
+    wfi
+    csrc sip, t1
+    j 1b
+2:
+    csrc sip, t1
+    sd zero, 0(t0)
+    fence
+
+    .globl _secondary_start
+_secondary_start:
+    # a0: hartid, a1: stack top
+    mv sp, a1
+    call rust_main_secondary
+
+    .section .data
+boot_hart_claimed:
+    .word 0
+no_boot_hart:
+    .string "[kernel] no hart id below MAX_CPU_NUM could boot\n"
+    .align 3
+    # stack top of each parked hart, set to release it
+    .globl hart_release
+hart_release:
+    .zero 8 * {max_cpu_num}
+
     .section .bss.stack
+    .align 12
+    # guard page and stack of each hart
     .globl boot_stack_lower_bound
 boot_stack_lower_bound:
-    .space 4096 * 16
+    .space {boot_stack_stride} * {max_cpu_num}
     .globl boot_stack_top
-boot_stack_top:
\ No newline at end of file
+boot_stack_top:
@@ -0,0 +1,28 @@
+//! Runner of `#[test_case]` kernel tests, `make test` boots the test kernel
+//! and QEMU exits with status 0 only if every test passed.
+
+use core::any::type_name;
+use crate::sbi::shutdown;
+use crate::{print, println};
+
+pub trait Testable {
+  fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+  fn run(&self) {
+    print!("test {} ... ", type_name::<T>());
+    self();
+    println!("ok");
+  }
+}
+
+/// A failing test panics, and panic handler shuts down with failure.
+pub fn test_runner(tests: &[&dyn Testable]) {
+  println!("running {} tests", tests.len());
+  for test in tests {
+    test.run();
+  }
+  println!("test result: ok. {} passed", tests.len());
+  shutdown(false);
+}
@@ -1,10 +1,31 @@
 use core::panic::PanicInfo;
+use riscv::register::{scause, sepc, sstatus, stval};
+use crate::common::cpuid;
+use crate::config::MAX_CPU_NUM;
 use crate::println;
+use crate::profiler;
 use crate::sbi::shutdown;
+use crate::smp::{begin_panic, online_harts, park_if_panicking};
 use crate::stack_trace::print_stack_trace;
+use crate::task::{current_cpu, lock_state_of};
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+  // profiling interrupts would overwrite trap CSRs in the report
+  unsafe {
+    sstatus::clear_sie();
+  }
+  let hartid = cpuid();
+  let online = online_harts();
+  match begin_panic() {
+    None => {}
+    Some(panic_hart) if panic_hart == hartid => {
+      println!("Panicked while panicking: {}", info.message().unwrap());
+      shutdown(true);
+    }
+    // the other hart reports
+    Some(_) => park_if_panicking(),
+  }
   if let Some(location) = info.location() {
     println!(
       "Panicked at {}:{} {}",
@@ -15,6 +36,33 @@ fn panic(info: &PanicInfo) -> ! {
   } else {
     println!("Panicked: {}", info.message().unwrap());
   }
+  print_panic_report(hartid, online);
   unsafe { print_stack_trace(); }
-  shutdown()
+  if profiler::has_samples() {
+    profiler::stop();
+    profiler::dump();
+  }
+  shutdown(true)
+}
+
+/// Print state of current hart and its task, other harts are stopped.
+fn print_panic_report(hartid: usize, online: usize) {
+  println!("== hart {} ==", hartid);
+  println!(
+    "sstatus: {:#x}, scause: {:#x}, stval: {:#x}, sepc: {:#x}",
+    sstatus::read().bits(), scause::read().bits(), stval::read(), sepc::read(),
+  );
+  for hart in (0..MAX_CPU_NUM).filter(|hart| online & 1 << hart != 0) {
+    let (noff, intena) = lock_state_of(hart);
+    println!("hart {}: noff {}, intena {}", hart, noff, intena);
+  }
+  match current_cpu().current() {
+    Some(task) => {
+      println!("== pid {} ==", task.get_pid());
+      println!("{}", task.inner_borrow_ptr().get_trap_cx());
+    }
+    None => {
+      println!("== no task ==");
+    }
+  }
 }
@@ -1,17 +1,196 @@
-use log::{Level, LevelFilter, Log, Metadata, Record};
+//! Kernel logger, configured by `log=` in boot arguments, e.g.
+//! `log=warn,mm=trace,task::oom=debug` sets the default level and levels of
+//! modules below `os::`. Records are also kept in a ring read by `dmesg`.
+
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use log::{Level, LevelFilter, Log, Metadata, Record, warn};
+use spin::{Mutex, Once};
+use crate::common::cpuid;
+use crate::config::CLOCK_FREQ;
 use crate::println;
+use crate::smp::is_online;
+use crate::task::current_cpu;
+use crate::timer::get_time;
+
+const MAX_MODULE_FILTERS: usize = 8;
+const LOG_RING_SIZE: usize = 1 << 14;
+// records kept in ring even if console filters drop them
+const RING_LEVEL: LevelFilter = LevelFilter::Warn;
+const LEVELS: [LevelFilter; 6] = [
+  LevelFilter::Off,
+  LevelFilter::Error,
+  LevelFilter::Warn,
+  LevelFilter::Info,
+  LevelFilter::Debug,
+  LevelFilter::Trace,
+];
+
+// level of modules without a filter, may change at runtime
+static DEFAULT_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Off as usize);
+static MODULE_FILTERS: Once<[Option<ModuleFilter>; MAX_MODULE_FILTERS]> = Once::new();
+// spin::Mutex, records are also logged while allocator locks are held
+static LOG_RING: Mutex<LogRing> = Mutex::new(LogRing::new());
+
+/// Most recent log lines, oldest whole lines are dropped when full.
+struct LogRing {
+  buf: [u8; LOG_RING_SIZE],
+  // bytes ever written, and where readable bytes start
+  head: usize,
+  tail: usize,
+}
+
+impl LogRing {
+  const fn new() -> Self {
+    Self {
+      buf: [0; LOG_RING_SIZE],
+      head: 0,
+      tail: 0,
+    }
+  }
+
+  fn byte(&self, pos: usize) -> u8 {
+    self.buf[pos % LOG_RING_SIZE]
+  }
+
+  fn len(&self) -> usize {
+    self.head - self.tail
+  }
+
+  fn push(&mut self, byte: u8) {
+    if self.len() == LOG_RING_SIZE {
+      while self.tail < self.head && self.byte(self.tail) != b'\n' {
+        self.tail += 1;
+      }
+      self.tail += 1;
+    }
+    self.buf[self.head % LOG_RING_SIZE] = byte;
+    self.head += 1;
+  }
+
+  /// Append whole lines among the last `len` bytes to `out`, which must
+  /// have room for them.
+  fn read(&self, len: usize, out: &mut Vec<u8>) {
+    let mut start = self.head - len.min(self.len());
+    if start > self.tail {
+      while start < self.head && self.byte(start - 1) != b'\n' {
+        start += 1;
+      }
+    }
+    out.extend((start..self.head).map(|pos| self.byte(pos)));
+  }
+
+  fn clear(&mut self) {
+    self.tail = self.head;
+  }
+}
+
+impl Write for LogRing {
+  fn write_str(&mut self, s: &str) -> fmt::Result {
+    s.bytes().for_each(|byte| self.push(byte));
+    Ok(())
+  }
+}
+
+#[derive(Clone, Copy)]
+struct ModuleFilter {
+  // path below crate root, e.g. `mm::frame_allocator`
+  module: &'static str,
+  level: LevelFilter,
+}
+
+impl ModuleFilter {
+  fn matches(&self, target: &str) -> bool {
+    let path = target.strip_prefix("os::").unwrap_or(target);
+    path.strip_prefix(self.module)
+      .map_or(false, |rest| rest.is_empty() || rest.starts_with("::"))
+  }
+}
+
+fn default_level() -> LevelFilter {
+  LEVELS[DEFAULT_LEVEL.load(Ordering::Relaxed)]
+}
+
+/// Level of the most specific filter matching `target`.
+fn level_of(target: &str) -> LevelFilter {
+  MODULE_FILTERS
+    .get()
+    .into_iter()
+    .flatten()
+    .flatten()
+    .filter(|filter| filter.matches(target))
+    .max_by_key(|filter| filter.module.len())
+    .map_or_else(default_level, |filter| filter.level)
+}
+
+/// `log` skips records above max level before asking the logger.
+fn update_max_level() {
+  let modules = MODULE_FILTERS.get().into_iter().flatten().flatten();
+  let max = modules.map(|filter| filter.level).fold(default_level().max(RING_LEVEL), Ord::max);
+  log::set_max_level(max);
+}
+
+fn current_pid() -> Option<usize> {
+  // processors are allocated on first use, which must wait for the heap
+  if !is_online(cpuid()) {
+    return None;
+  }
+  current_cpu().current().map(|task| task.get_pid())
+}
+
+/// Time, tag, hart and pid leading every log line.
+struct Header<'a> {
+  time: usize,
+  tag: &'a str,
+  hart: usize,
+  pid: Option<usize>,
+}
+
+impl<'a> Header<'a> {
+  fn new(tag: &'a str) -> Self {
+    Self {
+      time: get_time(),
+      tag,
+      hart: cpuid(),
+      pid: current_pid(),
+    }
+  }
+}
+
+impl fmt::Display for Header<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "[{:>5}.{:06}] [{:>5}] [hart {}] ",
+      self.time / CLOCK_FREQ,
+      self.time % CLOCK_FREQ * 1_000_000 / CLOCK_FREQ,
+      self.tag,
+      self.hart,
+    )?;
+    match self.pid {
+      Some(pid) => write!(f, "[pid {}]", pid),
+      None => write!(f, "[pid -]"),
+    }
+  }
+}
 
 struct SimpleLogger;
 
 impl Log for SimpleLogger {
-  fn enabled(&self, _metadata: &Metadata) -> bool {
-    true
+  fn enabled(&self, metadata: &Metadata) -> bool {
+    metadata.level() <= level_of(metadata.target()).max(RING_LEVEL)
   }
 
   fn log(&self, record: &Record) {
     if !self.enabled(record.metadata()) {
       return;
     }
+    let header = Header::new(record.level().as_str());
+    let _ = writeln!(LOG_RING.lock(), "{} {}", header, record.args());
+    if record.level() > level_of(record.target()) {
+      return;
+    }
     let color = match record.level() {
       Level::Error => 31,   // red
       Level::Warn => 93,    // yellow
@@ -19,19 +198,86 @@ impl Log for SimpleLogger {
       Level::Debug => 32,   // green
       Level::Trace => 90,   // gray
     };
-    println!(
-      "\x1b[{}m[{:>5}] {}\x1b[0m",
-      color,
-      record.level(),
-      record.args(),
-    );
+    println!("\x1b[{}m{} {}\x1b[0m", color, header, record.args());
   }
 
   fn flush(&self) {}
 }
 
-pub fn init(level: Option<LevelFilter>) {
+/// Write a line tagged `tag` regardless of filters, to console or else to
+/// the log ring only.
+pub fn log_line(tag: &str, args: fmt::Arguments, console: bool) {
+  let header = Header::new(tag);
+  if console {
+    println!("{} {}", header, args);
+  } else {
+    let _ = writeln!(LOG_RING.lock(), "{} {}", header, args);
+  }
+}
+
+/// Set level of modules without a filter.
+pub fn set_level(level: LevelFilter) {
+  DEFAULT_LEVEL.store(level as usize, Ordering::Relaxed);
+  update_max_level();
+}
+
+/// Last `len` bytes of the log ring cut to whole lines, then empty the
+/// ring if `clear`.
+pub fn read_log_ring(len: usize, clear: bool) -> Vec<u8> {
+  let len = len.min(LOG_RING_SIZE);
+  // allocate before locking, allocator may log
+  let mut lines = Vec::with_capacity(len);
+  let mut ring = LOG_RING.lock();
+  ring.read(len, &mut lines);
+  if clear {
+    ring.clear();
+  }
+  lines
+}
+
+pub fn clear_log_ring() {
+  LOG_RING.lock().clear();
+}
+
+pub fn log_ring_size() -> usize {
+  LOG_RING_SIZE
+}
+
+/// Level given by number as in `log`, 0 for off up to 5 for trace.
+pub fn level_from_usize(level: usize) -> Option<LevelFilter> {
+  LEVELS.get(level).copied()
+}
+
+/// Parse `log=` of `bootargs`, which must outlive the logger.
+pub fn init(bootargs: &'static str) {
   static LOGGER: SimpleLogger = SimpleLogger;
   log::set_logger(&LOGGER).unwrap();
-  log::set_max_level(level.unwrap_or(LevelFilter::Off));
-}
\ No newline at end of file
+  let mut invalid = None;
+  let mut filters = [None; MAX_MODULE_FILTERS];
+  let mut count = 0;
+  let spec = bootargs
+    .split_whitespace()
+    .find_map(|arg| arg.strip_prefix("log="))
+    .unwrap_or("");
+  for item in spec.split(',').filter(|item| !item.is_empty()) {
+    let parsed = match item.split_once('=') {
+      Some((module, level)) => level.parse().ok().and_then(|level| {
+        let slot = filters.get_mut(count)?;
+        *slot = Some(ModuleFilter { module, level });
+        count += 1;
+        Some(())
+      }),
+      None => item.parse::<LevelFilter>().ok().map(|level| {
+        DEFAULT_LEVEL.store(level as usize, Ordering::Relaxed);
+      }),
+    };
+    if parsed.is_none() {
+      invalid = Some(item);
+    }
+  }
+  MODULE_FILTERS.call_once(|| filters);
+  update_max_level();
+  if let Some(item) = invalid {
+    warn!("[kernel] ignored log filter {:?}", item);
+  }
+}
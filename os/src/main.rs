@@ -2,6 +2,9 @@
 #![feature(alloc_error_handler)]
 #![feature(stmt_expr_attributes)]
 #![feature(negative_impls)]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::ktest::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 #![no_std]
 #![no_main]
 
@@ -14,11 +17,13 @@ mod console;
 mod lang_items;
 mod sbi;
 mod logging;
+mod dtb;
 mod sync;
 mod trap;
 mod syscall;
 mod stack_trace;
 mod config;
+mod drivers;
 mod loader;
 mod task;
 mod timer;
@@ -26,16 +31,22 @@ mod mm;
 mod vars;
 mod common;
 mod debug;
+mod smp;
+mod profiler;
+#[cfg(test)]
+mod ktest;
 
 use core::arch::{asm, global_asm};
-use core::sync::atomic::AtomicU32;
-use core::sync::atomic::Ordering;
-use log::{info, LevelFilter, trace};
+use log::{info, trace};
 use vars::*;
-use crate::common::r_tp;
+use crate::config::{BOOT_STACK_SIZE, MAX_CPU_NUM, PAGE_SIZE};
 use crate::mm::KERNEL_SPACE;
 
-global_asm!(include_str!("entry.asm"));
+global_asm!(
+  include_str!("entry.asm"),
+  max_cpu_num = const MAX_CPU_NUM,
+  boot_stack_stride = const BOOT_STACK_SIZE + PAGE_SIZE,
+);
 global_asm!(include_str!("link_app.S"));
 
 pub fn clear_bss() {
@@ -44,56 +55,62 @@ pub fn clear_bss() {
   });
 }
 
-static STARTED: AtomicU32 = AtomicU32::new(0);
-
+/// Entry of boot hart, other harts are started after kernel is ready.
 #[no_mangle]
-pub fn rust_main(hartid: usize, _dtb: usize) -> ! {
+pub fn rust_main(hartid: usize, dtb: usize) -> ! {
   save_hartid_to_tp(hartid);
-  if r_tp() == 0 {
-    clear_bss();
-    logging::init(LevelFilter::Off.into());
-    info!("bss cleaned");
-    mm::init();
-    info!("mm inited");
-    mm::test();
-    info!(
-        "[kernel] .text [{:#x}, {:#x})",
-        stext as usize,
-        etext as usize
-    );
-    info!(
-        "[kernel] .rodata [{:#x}, {:#x})",
-        srodata as usize, erodata as usize
-    );
-    info!(
-        "[kernel] .data [{:#x}, {:#x})",
-        sdata as usize, edata as usize
-    );
-    info!(
-        "[kernel] boot_stack top=bottom={:#x}, lower_bound={:#x}",
-        boot_stack_top as usize, boot_stack_lower_bound as usize
-    );
-    info!("[kernel] .bss [{:#x}, {:#x})", sbss as usize, ebss as usize);
-    trap::init();
-    info!("trap inited");
-    trap::enable_timer_interrupt();
-    info!("timer interrupt opened");
-    timer::set_next_trigger();
-    task::init();
-    info!("being able to run initproc");
-    STARTED.store(1, Ordering::Release);
-  } else {
-    while STARTED.load(Ordering::Acquire) == 0 {}
-    trace!("hartid {} starting", r_tp());
-    KERNEL_SPACE.lock().activate();
-    trap::init();
-    trap::enable_timer_interrupt();
-    timer::set_next_trigger();
-  }
+  clear_bss();
+  sbi::init();
+  dtb::init(dtb);
+  logging::init(dtb::bootargs());
+  info!("bss cleaned");
+  sbi::print_info();
+  mm::init();
+  info!("mm inited");
+  info!(
+      "[kernel] .text [{:#x}, {:#x})",
+      stext as usize,
+      etext as usize
+  );
+  info!(
+      "[kernel] .rodata [{:#x}, {:#x})",
+      srodata as usize, erodata as usize
+  );
+  info!(
+      "[kernel] .data [{:#x}, {:#x})",
+      sdata as usize, edata as usize
+  );
+  info!(
+      "[kernel] boot_stack top=bottom={:#x}, lower_bound={:#x}",
+      boot_stack_top as usize, boot_stack_lower_bound as usize
+  );
+  info!("[kernel] .bss [{:#x}, {:#x})", sbss as usize, ebss as usize);
+  trap::init();
+  info!("trap inited");
+  timer::set_next_trigger();
+  task::init();
+  #[cfg(test)]
+  test_main();
+  info!("being able to run initproc");
+  smp::set_online();
+  smp::start_secondary_harts();
   task::scheduler();
   panic!("Unreachable in rust_main")
 }
 
+/// Entry of harts started by [`smp::start_hart`].
+#[no_mangle]
+pub fn rust_main_secondary(hartid: usize) -> ! {
+  save_hartid_to_tp(hartid);
+  trace!("hartid {} starting", hartid);
+  KERNEL_SPACE.lock().activate();
+  trap::init();
+  timer::set_next_trigger();
+  smp::set_online();
+  task::scheduler();
+  panic!("Unreachable in rust_main_secondary")
+}
+
 fn save_hartid_to_tp(hartid: usize) {
   unsafe {
     asm!(
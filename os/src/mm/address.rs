@@ -1,287 +1,26 @@
-use core::fmt::{self, Debug, Formatter};
-use core::mem::size_of;
-use crate::config::{PAGE_SIZE, PAGE_SIZE_BITS};
-use crate::mm::page_table::PageTableEntry;
+//! Address types are shared with host tests through `kcore`, kernel reaches
+//! physical memory through the identity mapping.
 
-const PA_WIDTH_SV39: usize = 56;
-const VA_WIDTH_SV39: usize = 39;
-const PPN_WIDTH_SV39: usize = PA_WIDTH_SV39 - PAGE_SIZE_BITS;
-const VPN_WIDTH_SV39: usize = VA_WIDTH_SV39 - PAGE_SIZE_BITS;
+use kcore::phys_mem::{Identity, PhysMem};
+pub use kcore::address::*;
 
-#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
-pub struct PhysAddr(pub usize);
-
-#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
-pub struct VirtAddr(pub usize);
-
-#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
-pub struct PhysPageNum(pub usize);
-
-#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
-pub struct VirtPageNum(pub usize);
-
-
-impl Debug for VirtAddr {
-  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-    f.write_fmt(format_args!("VA:{:#x}", self.0))
-  }
-}
-
-impl Debug for VirtPageNum {
-  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-    f.write_fmt(format_args!("VPN:{:#x}", self.0))
-  }
-}
-
-impl Debug for PhysAddr {
-  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-    f.write_fmt(format_args!("PA:{:#x}", self.0))
-  }
-}
-
-impl Debug for PhysPageNum {
-  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-    f.write_fmt(format_args!("PPN:{:#x}", self.0))
-  }
-}
-
-impl From<usize> for PhysAddr {
-  fn from(value: usize) -> Self {
-    Self(value & ((1 << PA_WIDTH_SV39) - 1))
-  }
-}
-
-impl From<usize> for PhysPageNum {
-  fn from(value: usize) -> Self {
-    Self(value & ((1 << PPN_WIDTH_SV39) - 1))
-  }
-}
-
-impl From<usize> for VirtAddr {
-  fn from(value: usize) -> Self {
-    Self(value & ((1 << VA_WIDTH_SV39) - 1))
-  }
-}
-
-impl From<usize> for VirtPageNum {
-  fn from(value: usize) -> Self {
-    Self(value & ((1 << VPN_WIDTH_SV39) - 1))
-  }
+/// Access to the frame of a physical page number.
+pub trait PhysPageAccess {
+  fn get_bytes_array(&self) -> &'static mut [u8];
+  fn get_mut<T>(&self) -> &'static mut T;
+  fn get_ptr_mut(&self) -> *mut u8;
 }
 
-impl From<PhysAddr> for usize {
-  fn from(value: PhysAddr) -> Self {
-    value.0
-  }
-}
-
-impl From<PhysPageNum> for usize {
-  fn from(value: PhysPageNum) -> Self {
-    value.0
-  }
-}
-
-impl From<VirtAddr> for usize {
-  fn from(value: VirtAddr) -> Self {
-    if value.0 >= (1 << (VA_WIDTH_SV39 - 1)) {
-      value.0 | (!((1 << VA_WIDTH_SV39) - 1))
-    } else {
-      value.0
-    }
-  }
-}
-
-impl From<VirtPageNum> for usize {
-  fn from(value: VirtPageNum) -> Self {
-    value.0
-  }
-}
-
-impl PhysAddr {
-  pub fn page_offset(&self) -> usize {
-    self.0 & (PAGE_SIZE - 1)
-  }
-
-  pub fn aligned(&self) -> bool {
-    self.page_offset() == 0
+impl PhysPageAccess for PhysPageNum {
+  fn get_bytes_array(&self) -> &'static mut [u8] {
+    Identity.get_bytes_array(*self)
   }
 
-  pub fn floor(&self) -> PhysPageNum {
-    PhysPageNum(self.0 / PAGE_SIZE)
+  fn get_mut<T>(&self) -> &'static mut T {
+    Identity.get_mut((*self).into())
   }
 
-  pub fn ceil(&self) -> PhysPageNum {
-    PhysPageNum((self.0 - 1 + PAGE_SIZE) / PAGE_SIZE)
+  fn get_ptr_mut(&self) -> *mut u8 {
+    Identity.ptr((*self).into())
   }
 }
-
-impl PhysAddr {
-  pub fn get_mut<T>(&self) -> &'static mut T {
-    unsafe { (self.0 as *mut T).as_mut().unwrap() }
-  }
-}
-
-impl From<PhysAddr> for PhysPageNum {
-  fn from(value: PhysAddr) -> Self {
-    assert_eq!(value.page_offset(), 0);
-    value.floor()
-  }
-}
-
-impl From<PhysPageNum> for PhysAddr {
-  fn from(value: PhysPageNum) -> Self {
-    Self(value.0 << PAGE_SIZE_BITS)
-  }
-}
-
-impl VirtAddr {
-  pub fn page_offset(&self) -> usize {
-    self.0 & (PAGE_SIZE - 1)
-  }
-
-  pub fn aligned(&self) -> bool {
-    self.page_offset() == 0
-  }
-
-  pub fn floor(&self) -> VirtPageNum {
-    VirtPageNum(self.0 / PAGE_SIZE)
-  }
-
-  pub fn ceil(&self) -> VirtPageNum {
-    VirtPageNum((self.0 - 1 + PAGE_SIZE) / PAGE_SIZE)
-  }
-}
-
-impl From<VirtAddr> for VirtPageNum {
-  fn from(value: VirtAddr) -> Self {
-    assert_eq!(value.page_offset(), 0);
-    value.floor()
-  }
-}
-
-impl From<VirtPageNum> for VirtAddr {
-  fn from(value: VirtPageNum) -> Self {
-    Self(value.0 << PAGE_SIZE_BITS)
-  }
-}
-
-impl PhysPageNum {
-  pub fn get_pte_array(&self) -> &'static mut [PageTableEntry] {
-    let pa: PhysAddr = self.clone().into();
-    unsafe { core::slice::from_raw_parts_mut(pa.0 as *mut _, PAGE_SIZE / size_of::<usize>()) }
-  }
-
-  pub fn get_bytes_array(&self) -> &'static mut [u8] {
-    let pa: PhysAddr = self.clone().into();
-    unsafe { core::slice::from_raw_parts_mut(pa.0 as *mut _, PAGE_SIZE) }
-  }
-
-  pub fn get_mut<T>(&self) -> &'static mut T {
-    let pa: PhysAddr = self.clone().into();
-    unsafe {
-      (pa.0 as *mut T).as_mut().unwrap()
-    }
-  }
-
-  pub fn get_ptr(&self) -> *const u8 {
-    let pa: PhysAddr = self.clone().into();
-    pa.0 as *const u8
-  }
-
-  pub fn get_ptr_mut(&self) -> *mut u8 {
-    let pa: PhysAddr = self.clone().into();
-    pa.0 as *mut u8
-  }
-}
-
-impl VirtPageNum {
-  pub fn indexes(&self) -> [usize; 3] {
-    let mut vpn = self.0;
-    let mut idx = [0usize; 3];
-    idx.iter_mut().rev()
-      .for_each(|x| {
-        *x = vpn & 511;
-        vpn >>= 9;
-      });
-    idx
-  }
-}
-
-pub trait StepByOne {
-  fn step(&mut self);
-}
-
-impl StepByOne for VirtPageNum {
-  fn step(&mut self) {
-    self.0 += 1;
-  }
-}
-
-#[derive(Copy, Clone)]
-pub struct SimpleRange<T>
-  where T: StepByOne + Copy + PartialEq + PartialOrd + Debug
-{
-  l: T,
-  r: T,
-}
-
-impl<T> SimpleRange<T>
-  where T: StepByOne + Copy + PartialEq + PartialOrd + Debug
-{
-  pub fn new(start: T, end: T) -> Self {
-    assert!(start <= end, "start {:?} > end {:?}!", start, end);
-    Self { l: start, r: end }
-  }
-
-  pub fn get_start(&self) -> T {
-    self.l
-  }
-
-  pub fn get_end(&self) -> T {
-    self.r
-  }
-}
-
-impl<T> IntoIterator for SimpleRange<T>
-  where T: StepByOne + Copy + PartialEq + PartialOrd + Debug
-{
-  type Item = T;
-  type IntoIter = SimpleRangeIterator<T>;
-
-  fn into_iter(self) -> Self::IntoIter {
-    SimpleRangeIterator::new(self.l, self.r)
-  }
-}
-
-pub struct SimpleRangeIterator<T>
-  where T: StepByOne + Copy + PartialEq + PartialOrd + Debug
-{
-  current: T,
-  end: T,
-}
-
-impl<T> SimpleRangeIterator<T>
-  where T: StepByOne + Copy + PartialEq + PartialOrd + Debug
-{
-  pub fn new(l: T, r: T) -> Self {
-    Self { current: l, end: r }
-  }
-}
-
-impl<T> Iterator for SimpleRangeIterator<T>
-  where T: StepByOne + Copy + PartialEq + PartialOrd + Debug
-{
-  type Item = T;
-
-  fn next(&mut self) -> Option<Self::Item> {
-    if self.current == self.end {
-      None
-    } else {
-      let ret = self.current;
-      self.current.step();
-      Some(ret)
-    }
-  }
-}
-
-pub type VPNRange = SimpleRange<VirtPageNum>;
@@ -0,0 +1,162 @@
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use log::info;
+use crate::common::cpuid;
+use crate::config::{MAX_CPU_NUM, PAGE_SIZE, PAGE_SIZE_BITS};
+use crate::mm::VirtPageNum;
+use crate::sbi::remote_sfence_vma_asid;
+use crate::sync::SpinMutex;
+
+pub const SATP_ASID_SHIFT: usize = 44;
+const ASID_FIELD_BITS: usize = 16;
+const ASID_FIELD_MASK: usize = (1 << ASID_FIELD_BITS) - 1;
+
+// largest ASID supported by hardware, 0 if ASID is not supported
+static MAX_ASID: AtomicUsize = AtomicUsize::new(0);
+// ASIDs are only valid in the generation they are allocated
+static GENERATION: AtomicUsize = AtomicUsize::new(1);
+
+lazy_static! {
+  // ASID 0 is kept for kernel space
+  static ref NEXT_ASID: SpinMutex<usize> = SpinMutex::new(1);
+  // generation whose stale entries have been flushed on each hart
+  static ref HART_GENERATION: Vec<AtomicUsize> =
+    (0..MAX_CPU_NUM).map(|_| AtomicUsize::new(0)).collect();
+}
+
+/// Find out how many ASID bits satp holds, kernel space must be active.
+pub fn init_asid() {
+  let satp: usize;
+  let probed: usize;
+  unsafe {
+    asm!("csrr {}, satp", out(reg) satp);
+    asm!("csrw satp, {}", in(reg) satp | ASID_FIELD_MASK << SATP_ASID_SHIFT);
+    asm!("csrr {}, satp", out(reg) probed);
+    asm!("csrw satp, {}", in(reg) satp);
+    asm!("sfence.vma");
+  }
+  let bits = (probed >> SATP_ASID_SHIFT & ASID_FIELD_MASK).count_ones();
+  MAX_ASID.store((1 << bits) - 1, Ordering::Relaxed);
+  info!("ASID bits: {}", bits);
+}
+
+/// ASID of an address space, reallocated after a generation rollover.
+pub struct Asid {
+  // generation << ASID_FIELD_BITS | asid, 0 if never allocated
+  context: AtomicUsize,
+  // harts which may hold stale entries of this address space
+  stale_harts: AtomicUsize,
+  // harts running with kernel space, shot down on flush
+  active_harts: AtomicUsize,
+  kernel: bool,
+}
+
+impl Asid {
+  pub fn new() -> Self {
+    Self {
+      context: AtomicUsize::new(0),
+      stale_harts: AtomicUsize::new(0),
+      active_harts: AtomicUsize::new(0),
+      kernel: false,
+    }
+  }
+
+  /// ASID 0, which is never reallocated.
+  pub fn kernel() -> Self {
+    Self {
+      kernel: true,
+      ..Self::new()
+    }
+  }
+
+  /// Record current hart switched to kernel space.
+  pub fn activate_kernel(&self) {
+    assert!(self.kernel);
+    self.active_harts.fetch_or(1 << cpuid(), Ordering::AcqRel);
+  }
+
+  /// Make sure ASID is valid and TLB of current hart holds no stale
+  /// entries for it, return the ASID.
+  pub fn activate(&self) -> usize {
+    if self.kernel || MAX_ASID.load(Ordering::Relaxed) == 0 {
+      // trampoline flushes whole TLB when ASID is 0
+      return 0;
+    }
+    let mut context = self.context.load(Ordering::Acquire);
+    if context >> ASID_FIELD_BITS != GENERATION.load(Ordering::Acquire) {
+      context = self.realloc();
+    }
+    let hart = cpuid();
+    let stale = self.stale_harts.fetch_and(!(1 << hart), Ordering::AcqRel) & (1 << hart) != 0;
+    let generation = context >> ASID_FIELD_BITS;
+    let asid = context & ASID_FIELD_MASK;
+    if HART_GENERATION[hart].load(Ordering::Relaxed) < generation {
+      // ASIDs of previous generation may be cached
+      unsafe {
+        asm!("sfence.vma");
+      }
+      HART_GENERATION[hart].store(generation, Ordering::Relaxed);
+    } else if stale {
+      unsafe {
+        asm!("sfence.vma zero, {}", in(reg) asid);
+      }
+    }
+    asid
+  }
+
+  /// Flush `vpn`, or the whole address space if `None`, on current hart.
+  /// Kernel space is shot down on other harts before returning, other
+  /// harts flush user spaces before running them again.
+  pub fn flush(&self, vpn: Option<VirtPageNum>) {
+    let asid = if self.kernel {
+      0
+    } else {
+      let context = self.context.load(Ordering::Acquire);
+      if context >> ASID_FIELD_BITS != GENERATION.load(Ordering::Acquire) {
+        // no TLB holds entries of it in current generation
+        return;
+      }
+      context & ASID_FIELD_MASK
+    };
+    let others = ((1 << MAX_CPU_NUM) - 1) & !(1 << cpuid());
+    if self.kernel {
+      let harts = self.active_harts.load(Ordering::Acquire) & others;
+      if harts != 0 {
+        match vpn {
+          Some(vpn) => remote_sfence_vma_asid(harts, vpn.0 << PAGE_SIZE_BITS, PAGE_SIZE, asid),
+          None => remote_sfence_vma_asid(harts, 0, usize::MAX, asid),
+        }
+      }
+    } else {
+      self.stale_harts.fetch_or(others, Ordering::AcqRel);
+    }
+    unsafe {
+      match vpn {
+        Some(vpn) => asm!("sfence.vma {}, {}", in(reg) vpn.0 << PAGE_SIZE_BITS, in(reg) asid),
+        None => asm!("sfence.vma zero, {}", in(reg) asid),
+      }
+    }
+  }
+
+  fn realloc(&self) -> usize {
+    let mut next = NEXT_ASID.lock();
+    let mut generation = GENERATION.load(Ordering::Acquire);
+    let context = self.context.load(Ordering::Acquire);
+    if context >> ASID_FIELD_BITS == generation {
+      return context;
+    }
+    if *next > MAX_ASID.load(Ordering::Relaxed) {
+      // every hart flushes its whole TLB before using the new generation
+      generation += 1;
+      GENERATION.store(generation, Ordering::Release);
+      *next = 1;
+    }
+    let context = generation << ASID_FIELD_BITS | *next;
+    *next += 1;
+    self.context.store(context, Ordering::Release);
+    self.stale_harts.store(0, Ordering::Release);
+    context
+  }
+}
@@ -1,63 +1,15 @@
+#[cfg(test)]
 use alloc::vec::Vec;
 use core::fmt::{self, Debug, Formatter};
+use kcore::frame_allocator::{FrameAllocator, StackFrameAllocator};
 use lazy_static::lazy_static;
+#[cfg(test)]
 use log::trace;
 use crate::config::MEMORY_END;
 use crate::sync::SpinMutex;
-use crate::mm::address::{PhysAddr, PhysPageNum};
+use crate::mm::address::{PhysAddr, PhysPageAccess, PhysPageNum};
 use crate::vars::*;
 
-trait FrameAllocator {
-  fn new() -> Self;
-  fn alloc(&mut self) -> Option<PhysPageNum>;
-  fn dealloc(&mut self, ppn: PhysPageNum);
-}
-
-pub struct StackFrameAllocator {
-  current: usize,
-  end: usize,
-  recycled: Vec<usize>,
-}
-
-impl StackFrameAllocator {
-  pub fn init(&mut self, l: PhysPageNum, r: PhysPageNum) {
-    self.current = l.0;
-    self.end = r.0;
-  }
-}
-
-impl FrameAllocator for StackFrameAllocator {
-  fn new() -> Self {
-    Self {
-      current: 0,
-      end: 0,
-      recycled: Vec::new(),
-    }
-  }
-
-  fn alloc(&mut self) -> Option<PhysPageNum> {
-    if let Some(ppn) = self.recycled.pop() {
-      Some(ppn.into())
-    } else if self.current == self.end {
-      None
-    } else {
-      self.current += 1;
-      Some((self.current - 1).into())
-    }
-  }
-
-  fn dealloc(&mut self, ppn: PhysPageNum) {
-    let ppn = ppn.0;
-    if ppn >= self.current || self.recycled
-      .iter()
-      .find(|&v| *v == ppn)
-      .is_some() {
-      panic!("Frame ppn={:#x} has not been allocated!", ppn);
-    }
-    self.recycled.push(ppn);
-  }
-}
-
 type FrameAllocatorImpl = StackFrameAllocator;
 lazy_static! {
   pub static ref FRAME_ALLOCATOR: SpinMutex<FrameAllocatorImpl> =
@@ -110,8 +62,22 @@ pub fn frame_dealloc(ppn: PhysPageNum) {
     .dealloc(ppn);
 }
 
-#[allow(unused)]
-pub fn frame_allocator_test() {
+/// Allocate contiguous frames without zeroing them, they are not tracked
+/// and must be freed by [`frame_dealloc_contiguous`].
+pub fn frame_alloc_contiguous(pages: usize, align: usize) -> Option<PhysPageNum> {
+  FRAME_ALLOCATOR
+    .lock()
+    .alloc_contiguous(pages, align)
+}
+
+pub fn frame_dealloc_contiguous(start: PhysPageNum, pages: usize) {
+  FRAME_ALLOCATOR
+    .lock()
+    .dealloc_contiguous(start, pages);
+}
+
+#[test_case]
+fn frame_allocator_test() {
   let mut v: Vec<FrameTracker> = Vec::new();
   for i in 0..5 {
     let frame = frame_alloc().unwrap();
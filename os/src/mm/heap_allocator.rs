@@ -1,28 +1,228 @@
-use buddy_system_allocator::LockedHeap;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{null_mut, NonNull};
+use buddy_system_allocator::Heap;
+use log::debug;
+#[cfg(test)]
 use log::trace;
-use crate::config::KERNEL_HEAP_SIZE;
+use spin::Mutex;
+use crate::config::{KERNEL_HEAP_GROW_SIZE, KERNEL_HEAP_SIZE, PAGE_SIZE};
+use crate::mm::address::{PhysAddr, PhysPageNum};
+use crate::mm::frame_allocator::{frame_alloc_contiguous, frame_dealloc_contiguous};
+use crate::mm::slab::{self, SLAB_SIZE};
+#[cfg(test)]
 use crate::vars::{ebss, sbss};
 
+const HEAP_ORDER: usize = 32;
+// most chunks grown from frames at the same time
+const MAX_HEAP_CHUNKS: usize = 64;
+// empty chunks kept when freeing, so that heap does not grow and shrink
+// on every allocation at the boundary
+const SPARE_HEAP_CHUNKS: usize = 1;
+
+// lock of allocator must not allocate, SpinMutex allocates processors
+// on first use
 #[global_allocator]
-static HEAP_ALLOCATOR: LockedHeap<32> = LockedHeap::empty();
+static HEAP_ALLOCATOR: LockedKernelHeap = LockedKernelHeap(Mutex::new(KernelHeap::empty()));
 
 #[alloc_error_handler]
 pub fn handle_alloc_error(layout: core::alloc::Layout) -> ! {
-  panic!("Heap allocation error, layout = {:?}", layout)
+  panic!("Heap allocation error, layout = {:?}, {:?}", layout, heap_stats())
 }
 
 static mut HEAP_SPACE: [u8; KERNEL_HEAP_SIZE] = [0; KERNEL_HEAP_SIZE];
 
+/// Contiguous frames added to heap when `HEAP_SPACE` runs out.
+struct HeapChunk {
+  heap: Heap<HEAP_ORDER>,
+  start: PhysPageNum,
+  pages: usize,
+}
+
+impl HeapChunk {
+  fn contains(&self, addr: usize) -> bool {
+    let start = PhysAddr::from(self.start).0;
+    (start..start + self.pages * PAGE_SIZE).contains(&addr)
+  }
+
+  fn is_empty(&self) -> bool {
+    self.heap.stats_alloc_actual() == 0
+  }
+}
+
+/// Heap usage in bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+  /// bytes managed by heap, including chunks
+  pub total: usize,
+  /// bytes allocated, rounded up to power of two
+  pub actual: usize,
+  /// bytes requested by allocations
+  pub user: usize,
+  /// number of chunks grown from frames
+  pub chunks: usize,
+}
+
+struct KernelHeap {
+  initial: Heap<HEAP_ORDER>,
+  chunks: [Option<HeapChunk>; MAX_HEAP_CHUNKS],
+}
+
+const NO_CHUNK: Option<HeapChunk> = None;
+
+impl KernelHeap {
+  const fn empty() -> Self {
+    Self {
+      initial: Heap::empty(),
+      chunks: [NO_CHUNK; MAX_HEAP_CHUNKS],
+    }
+  }
+
+  fn alloc(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+    if let Ok(ptr) = self.initial.alloc(layout) {
+      return Some(ptr);
+    }
+    for chunk in self.chunks.iter_mut().flatten() {
+      if let Ok(ptr) = chunk.heap.alloc(layout) {
+        return Some(ptr);
+      }
+    }
+    self.grow(layout)?.heap.alloc(layout).ok()
+  }
+
+  fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+    let addr = ptr.as_ptr() as usize;
+    match self.chunks.iter_mut().flatten().find(|chunk| chunk.contains(addr)) {
+      Some(chunk) => {
+        chunk.heap.dealloc(ptr, layout);
+        if chunk.is_empty() {
+          self.shrink(SPARE_HEAP_CHUNKS);
+        }
+      }
+      None => self.initial.dealloc(ptr, layout),
+    }
+  }
+
+  /// Add a chunk large enough for `layout`, return `None` if there is no
+  /// free slot or frame.
+  fn grow(&mut self, layout: Layout) -> Option<&mut HeapChunk> {
+    let slot = self.chunks.iter().position(Option::is_none)?;
+    // buddy blocks must be aligned to their size
+    let block = layout.size().max(layout.align()).next_power_of_two();
+    let pages = block.max(KERNEL_HEAP_GROW_SIZE) / PAGE_SIZE;
+    let start = frame_alloc_contiguous(pages, pages)?;
+    let mut heap = Heap::empty();
+    unsafe {
+      let addr = PhysAddr::from(start).0;
+      heap.add_to_heap(addr, addr + pages * PAGE_SIZE);
+    }
+    debug!("[kernel] heap grows {} pages at {:#x}", pages, start.0);
+    Some(self.chunks[slot].insert(HeapChunk { heap, start, pages }))
+  }
+
+  /// Free empty chunks except `keep` of them, return number of pages freed.
+  fn shrink(&mut self, mut keep: usize) -> usize {
+    let mut freed = 0;
+    for slot in self.chunks.iter_mut() {
+      if !slot.as_ref().map_or(false, HeapChunk::is_empty) {
+        continue;
+      }
+      if keep > 0 {
+        keep -= 1;
+        continue;
+      }
+      let chunk = slot.take().unwrap();
+      frame_dealloc_contiguous(chunk.start, chunk.pages);
+      debug!("[kernel] heap shrinks {} pages at {:#x}", chunk.pages, chunk.start.0);
+      freed += chunk.pages;
+    }
+    freed
+  }
+
+  fn stats(&self) -> HeapStats {
+    let mut stats = HeapStats {
+      total: self.initial.stats_total_bytes(),
+      actual: self.initial.stats_alloc_actual(),
+      user: self.initial.stats_alloc_user(),
+      chunks: 0,
+    };
+    for chunk in self.chunks.iter().flatten() {
+      stats.total += chunk.heap.stats_total_bytes();
+      stats.actual += chunk.heap.stats_alloc_actual();
+      stats.user += chunk.heap.stats_alloc_user();
+      stats.chunks += 1;
+    }
+    stats
+  }
+}
+
+struct LockedKernelHeap(Mutex<KernelHeap>);
+
+impl LockedKernelHeap {
+  fn alloc_buddy(&self, layout: Layout) -> *mut u8 {
+    self.0
+      .lock()
+      .alloc(layout)
+      .map_or(null_mut(), NonNull::as_ptr)
+  }
+
+  unsafe fn dealloc_buddy(&self, ptr: *mut u8, layout: Layout) {
+    self.0
+      .lock()
+      .dealloc(NonNull::new_unchecked(ptr), layout);
+  }
+}
+
+/// Small allocations are served by object caches of [`slab`].
+unsafe impl GlobalAlloc for LockedKernelHeap {
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    match slab::cache_index(&layout) {
+      Some(index) => slab::alloc(index),
+      None => self.alloc_buddy(layout),
+    }
+  }
+
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    match slab::cache_index(&layout) {
+      Some(index) => slab::dealloc(index, ptr),
+      None => self.dealloc_buddy(ptr, layout),
+    }
+  }
+}
+
+const SLAB_LAYOUT: Layout = unsafe { Layout::from_size_align_unchecked(SLAB_SIZE, SLAB_SIZE) };
+
+pub fn alloc_slab() -> Option<*mut u8> {
+  let slab = HEAP_ALLOCATOR.alloc_buddy(SLAB_LAYOUT);
+  (!slab.is_null()).then_some(slab)
+}
+
+pub fn dealloc_slab(slab: *mut u8) {
+  unsafe { HEAP_ALLOCATOR.dealloc_buddy(slab, SLAB_LAYOUT) }
+}
+
 pub fn init_heap() {
   unsafe {
     HEAP_ALLOCATOR
+      .0
       .lock()
+      .initial
       .init(HEAP_SPACE.as_ptr() as usize, KERNEL_HEAP_SIZE);
   }
 }
 
-#[allow(unused)]
-pub fn heap_test() {
+pub fn heap_stats() -> HeapStats {
+  HEAP_ALLOCATOR.0.lock().stats()
+}
+
+/// Free empty slabs and then every empty chunk, return number of pages
+/// freed.
+pub fn shrink_heap() -> usize {
+  slab::shrink_caches();
+  HEAP_ALLOCATOR.0.lock().shrink(0)
+}
+
+#[test_case]
+fn heap_test() {
   use alloc::boxed::Box;
   use alloc::vec::Vec;
   let bss_range = sbss as usize..ebss as usize;
@@ -39,5 +239,13 @@ pub fn heap_test() {
   }
   assert!(bss_range.contains(&(v.as_ptr() as usize)));
   drop(v);
+  // larger than whole initial heap, must come from a chunk
+  let before = heap_stats();
+  let big: Vec<u8> = Vec::with_capacity(KERNEL_HEAP_SIZE);
+  assert!(!bss_range.contains(&(big.as_ptr() as usize)));
+  assert!(heap_stats().chunks > before.chunks);
+  drop(big);
+  shrink_heap();
+  assert_eq!(heap_stats().chunks, before.chunks);
   trace!("heap_test passed!");
 }
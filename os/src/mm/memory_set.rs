@@ -1,5 +1,5 @@
 use alloc::collections::BTreeMap;
-use alloc::sync::Arc;
+use alloc::sync::{Arc, Weak};
 use core::arch::asm;
 use core::ptr;
 use lazy_static::lazy_static;
@@ -9,30 +9,48 @@ use crate::config::*;
 
 use crate::mm::{
   PageTableEntry,
-  address::{PhysAddr, PhysPageNum, VirtAddr, VirtPageNum, VPNRange},
-  frame_allocator::frame_alloc,
-  page_table::{MapArgs, PageTable, PTEFlags, UnmapArgs},
+  address::{PhysAddr, PhysPageAccess, PhysPageNum, VirtAddr, VirtPageNum, VPNRange},
+  asid::{Asid, SATP_ASID_SHIFT},
+  frame_allocator::{frame_alloc, FrameTracker},
+  page_table::{level_pages, MapArgs, PageTable, PTEFlags, UnmapArgs},
+  swap::{swap_enabled, swap_free, swap_read, swap_write},
 };
 
+use crate::dtb::has_test_device;
+use crate::smp::boot_stack_position;
 use crate::sync::SpinMutex;
 use crate::vars::*;
 
 lazy_static! {
   pub static ref KERNEL_SPACE: Arc<SpinMutex<MemorySet>> =
     Arc::new(SpinMutex::new(MemorySet::new_kernel()));
+  // loaded read-only ELF pages, keyed by segment data address and vpn
+  static ref SHARED_ELF_PAGES: SpinMutex<BTreeMap<(usize, VirtPageNum), Weak<FrameTracker>>> =
+    SpinMutex::new(BTreeMap::new());
 }
 
 pub struct MemorySet {
   page_table: PageTable,
+  asid: Asid,
   areas: BTreeMap<VirtPageNum, MapArea>,
+  // where the next swap out scan starts
+  clock_hand: VirtPageNum,
+  // user stack is [stack_bottom, TRAP_CONTEXT), growing down to the limit
+  stack_bottom: VirtPageNum,
+  stack_limit: usize,
 }
 
 impl MemorySet {
-  pub fn new_bare() -> Self {
-    Self {
-      page_table: PageTable::new(),
+  /// Return `None` if there is no frame left for the root page table.
+  pub fn new_bare() -> Option<Self> {
+    Some(Self {
+      page_table: PageTable::new()?,
+      asid: Asid::new(),
       areas: BTreeMap::new(),
-    }
+      clock_hand: VirtPageNum(0),
+      stack_bottom: VirtAddr::from(TRAP_CONTEXT).floor(),
+      stack_limit: USER_STACK_LIMIT,
+    })
   }
 
   /// Map new kernel without kernel stack.
@@ -51,9 +69,10 @@ impl MemorySet {
   /// +-------------------+  <- BASE_ADDRESS
   /// ```
   pub fn new_kernel() -> Self {
-    let mut memory_set = Self::new_bare();
+    let mut memory_set = Self::new_bare().unwrap();
+    memory_set.asid = Asid::kernel();
     // map trampoline
-    memory_set.map_trampoline();
+    memory_set.map_trampoline().unwrap();
     // print out sections information
     debug!(".text [{:#x}, {:#x})", stext as usize, etext as usize);
     debug!(".rodata [{:#x}, {:#x})", srodata as usize, erodata as usize);
@@ -66,7 +85,7 @@ impl MemorySet {
       (etext as usize).into(),
       MapType::Identical,
       MapPermission::R | MapPermission::X,
-    ), None);
+    ), None).unwrap();
     debug!("kernel.text section mapped");
 
     // map .rodata section
@@ -75,7 +94,7 @@ impl MemorySet {
       (erodata as usize).into(),
       MapType::Identical,
       MapPermission::R,
-    ), None);
+    ), None).unwrap();
     debug!("kernel.rodata section mapped");
 
     // map .data section
@@ -84,16 +103,28 @@ impl MemorySet {
       (edata as usize).into(),
       MapType::Identical,
       MapPermission::R | MapPermission::W,
-    ), None);
+    ), None).unwrap();
     debug!("kernel.data section mapped");
 
+    // map boot stacks, guard pages below them are left unmapped
+    for hartid in 0..MAX_CPU_NUM {
+      let (bottom, top) = boot_stack_position(hartid);
+      memory_set.push(MapArea::new(
+        bottom.into(),
+        top.into(),
+        MapType::Identical,
+        MapPermission::R | MapPermission::W,
+      ), None).unwrap();
+    }
+    debug!("kernel.boot stacks mapped");
+
     // map .bss section
     memory_set.push(MapArea::new(
-      (sbss_with_stack as usize).into(),
+      (sbss as usize).into(),
       (ebss as usize).into(),
       MapType::Identical,
       MapPermission::R | MapPermission::W,
-    ), None);
+    ), None).unwrap();
     debug!("kernel.bss section mapped");
 
     // map physical memory
@@ -102,12 +133,26 @@ impl MemorySet {
       MEMORY_END.into(),
       MapType::Identical,
       MapPermission::R | MapPermission::W,
-    ), None);
+    ), None).unwrap();
     debug!("kernel.physical memory mapped");
+
+    // map MMIO
+    let test_device = has_test_device().then_some((VIRT_TEST, PAGE_SIZE));
+    for &(start, len) in MMIO.iter().chain(test_device.iter()) {
+      memory_set.push(MapArea::new(
+        start.into(),
+        (start + len).into(),
+        MapType::Identical,
+        MapPermission::R | MapPermission::W,
+      ), None).unwrap();
+    }
+    debug!("kernel.MMIO mapped");
     memory_set
   }
 
   /// Make [`MemorySet`] from elf file, with `user_stack_top` and `entry_point` return.
+  /// Segments are not loaded until their pages are accessed.
+  /// Return `None` if physical frames run out.
   /// # ELF Layout:
   /// ```
   /// High 256GB
@@ -117,7 +162,11 @@ impl MemorySet {
   /// |    TrapContext    |
   /// +-------------------+  <- TRAP_CONTEXT, user_stack_top
   /// |    User Stack     |
-  /// +-------------------+  <- user_stack_bottom
+  /// +-------------------+  <- user_stack_bottom, grows down on page fault
+  /// |   Stack Growth    |
+  /// +-------------------+  <- user_stack_top - stack_limit
+  /// |    Guard Region   |
+  /// +-------------------+
   /// |       ...         |
   ///
   /// Low 256GB
@@ -133,9 +182,9 @@ impl MemorySet {
   /// +-------------------+
   /// |      .text        |
   /// +-------------------+  <- BASE_ADDRESS (0x10000 va)
-  pub fn from_elf(elf_data: &[u8]) -> (Self, usize, usize, usize) {
-    let mut memory_set = Self::new_bare();
-    memory_set.map_trampoline();
+  pub fn from_elf(elf_data: &'static [u8]) -> Option<(Self, usize, usize, usize)> {
+    let mut memory_set = Self::new_bare()?;
+    memory_set.map_trampoline()?;
     let elf = xmas_elf::ElfFile::new(elf_data).unwrap();
 
     // map elf file at low address
@@ -154,12 +203,10 @@ impl MemorySet {
         if ph_flags.is_read() { map_perm |= MapPermission::R; }
         if ph_flags.is_write() { map_perm |= MapPermission::W; }
         if ph_flags.is_execute() { map_perm |= MapPermission::X; }
-        let map_area = MapArea::new(start_va, end_va, MapType::Framed, map_perm);
+        let data = &elf_data[ph.offset() as usize..(ph.offset() + ph.file_size()) as usize];
+        let map_area = MapArea::new_elf(start_va, end_va, map_perm, data);
         max_end_vpn = max_end_vpn.max(map_area.vpn_range.get_end());
-        memory_set.push(
-          map_area,
-          Some(&elf.input[ph.offset() as usize..(ph.offset() + ph.file_size()) as usize]),
-        );
+        memory_set.push(map_area, None)?;
       }
     }
 
@@ -173,7 +220,8 @@ impl MemorySet {
       user_stack_top.into(),
       MapType::Framed,
       MapPermission::R | MapPermission::W | MapPermission::U,
-    ), None);
+    ), None)?;
+    memory_set.stack_bottom = VirtAddr::from(user_stack_bottom).floor();
 
     // map for sbrk
     memory_set.push(MapArea::new(
@@ -181,7 +229,7 @@ impl MemorySet {
       heap_bottom.into(),
       MapType::Framed,
       MapPermission::R | MapPermission::W | MapPermission::U,
-    ), None);
+    ), None)?;
 
     // map TrapContext
     memory_set.push(MapArea::new(
@@ -189,44 +237,57 @@ impl MemorySet {
       TRAMPOLINE.into(),
       MapType::Framed,
       MapPermission::R | MapPermission::W,
-    ), None);
-    (
+    ), None)?;
+    Some((
       memory_set,
       user_stack_top,
       heap_bottom,
       elf.header.pt2.entry_point() as usize
-    )
+    ))
   }
 
-  pub fn from_another(another: &MemorySet) -> Self {
+  /// Return `None` if physical frames run out.
+  pub fn from_another(another: &MemorySet) -> Option<Self> {
     // TODO: may do COW here
-    let mut memory_set = Self::new_bare();
-    memory_set.map_trampoline();
+    let mut memory_set = Self::new_bare()?;
+    memory_set.map_trampoline()?;
+    memory_set.stack_bottom = another.stack_bottom;
+    memory_set.stack_limit = another.stack_limit;
     for (start_vpn, ma) in another.areas.iter() {
+      if ma.elf.is_some() {
+        let mut area = ma.clone();
+        area.copy_loaded_pages(&another.page_table, &mut memory_set.page_table)?;
+        memory_set.areas.insert(*start_vpn, area);
+        continue;
+      }
       memory_set.areas.insert(start_vpn.clone(), ma.clone());
-      memory_set.push(ma.clone(), None);
+      memory_set.push(ma.clone(), None)?;
       for vpn in ma.vpn_range {
-        let src_ppn = another.page_table.translate(vpn).unwrap().ppn();
+        let src_pte = another.page_table.translate(vpn).unwrap();
         let dst_ppn = memory_set.page_table.translate(vpn).unwrap().ppn();
-        dst_ppn.get_bytes_array()
-          .copy_from_slice(src_ppn.get_bytes_array());
+        if src_pte.is_swapped() {
+          swap_read(src_pte.swap_slot(), dst_ppn.get_bytes_array());
+        } else {
+          dst_ppn.get_bytes_array()
+            .copy_from_slice(src_pte.ppn().get_bytes_array());
+        }
       }
     }
-    memory_set
+    Some(memory_set)
   }
 
-  fn map_trampoline(&mut self) {
+  fn map_trampoline(&mut self) -> Option<()> {
     self.page_table.map(
       MapArgs::builder(
         VirtAddr::from(TRAMPOLINE).into(),
         PhysAddr::from(strampoline as usize).into(),
       ).with_flags(PTEFlags::R | PTEFlags::X),
-    );
+    )
   }
 }
 
 impl MemorySet {
-  /// Insert VA to PTE
+  /// Insert VA to PTE, return `None` if physical frames run out.
   /// # Safety
   /// VPNRange must not overlap with other areas.
   pub unsafe fn insert_framed_area(
@@ -234,13 +295,13 @@ impl MemorySet {
     start_va: VirtAddr,
     end_va: VirtAddr,
     permission: MapPermission,
-  ) {
+  ) -> Option<()> {
     self.push(MapArea::new(
       start_va,
       end_va,
       MapType::Framed,
       permission,
-    ), None);
+    ), None)
   }
 
   #[allow(unused)]
@@ -256,6 +317,7 @@ impl MemorySet {
           .with_panic(false),
       );
       self.areas.remove(&vpn);
+      self.asid.flush(Some(vpn));
     }
   }
 
@@ -264,13 +326,16 @@ impl MemorySet {
       Some(area) => {
         area.unmap(&mut self.page_table);
         self.areas.remove(&start_vpn);
+        self.asid.flush(None);
       }
       None => return,
     }
   }
 
+  /// Switch current hart to kernel space.
   pub fn activate(&self) {
     let satp = self.page_table.token();
+    self.asid.activate_kernel();
     unsafe {
       satp::write(satp);
       asm!("sfence.vma");
@@ -281,15 +346,178 @@ impl MemorySet {
     self.page_table.token()
   }
 
+  /// Token with ASID to switch to this address space on current hart,
+  /// stale TLB entries of current hart are flushed.
+  pub fn switch_token(&self) -> usize {
+    self.page_table.token() | self.asid.activate() << SATP_ASID_SHIFT
+  }
+
+  /// Flush TLB entries of `vpn`, or the whole address space if `None`.
+  pub fn flush_tlb(&self, vpn: Option<VirtPageNum>) {
+    self.asid.flush(vpn);
+  }
+
   pub fn translate(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
     self.page_table.translate(vpn)
   }
 
+  /// Physical frames currently resident in this address space.
+  pub fn resident_pages(&self) -> usize {
+    self.page_table.frames_count()
+  }
+
+  pub fn is_swapped(&self, vpn: VirtPageNum) -> bool {
+    self.page_table.translate(vpn).map_or(false, |pte| pte.is_swapped())
+  }
+
+  /// Whether `vpn` belongs to an ELF segment but has not been loaded.
+  pub fn is_unloaded(&self, vpn: VirtPageNum) -> bool {
+    match self.page_table.translate(vpn) {
+      Some(pte) if pte.is_valid() || pte.is_swapped() => false,
+      _ => self.area_start(vpn)
+        .map_or(false, |start| self.areas[&start].elf.is_some()),
+    }
+  }
+
+  /// Make a swapped out, unloaded or stack growth `vpn` resident, nothing
+  /// is done for other pages. Return `None` if physical frames run out.
+  pub fn fault_in(&mut self, vpn: VirtPageNum) -> Option<()> {
+    if self.is_swapped(vpn) {
+      return self.swap_in(vpn);
+    }
+    if self.is_stack_growth(vpn) {
+      return self.grow_stack(vpn);
+    }
+    if !self.is_unloaded(vpn) {
+      return Some(());
+    }
+    let start = self.area_start(vpn).unwrap();
+    self.areas.get_mut(&start).unwrap().load_page(&mut self.page_table, vpn)
+  }
+
+  /// Whether `vpn` is below user stack but within stack limit.
+  pub fn is_stack_growth(&self, vpn: VirtPageNum) -> bool {
+    vpn < self.stack_bottom && vpn >= self.stack_limit_bottom()
+  }
+
+  /// Whether `vpn` is in the guard region below stack limit.
+  pub fn is_stack_guard(&self, vpn: VirtPageNum) -> bool {
+    let limit_bottom = self.stack_limit_bottom();
+    vpn < limit_bottom && vpn.0 + USER_STACK_GUARD / PAGE_SIZE >= limit_bottom.0
+  }
+
+  /// Extend user stack down to `vpn`, return `None` if physical frames run out.
+  pub fn grow_stack(&mut self, vpn: VirtPageNum) -> Option<()> {
+    assert!(self.is_stack_growth(vpn), "vpn {:?} is out of stack limit", vpn);
+    let mut area = self.areas.remove(&self.stack_bottom).unwrap();
+    if area.prepend_to(&mut self.page_table, vpn).is_none() {
+      self.areas.insert(self.stack_bottom, area);
+      return None;
+    }
+    self.areas.insert(vpn, area);
+    self.stack_bottom = vpn;
+    Some(())
+  }
+
+  fn stack_limit_bottom(&self) -> VirtPageNum {
+    VirtAddr::from(TRAP_CONTEXT - self.stack_limit).floor()
+  }
+
+  /// Start vpn of the area containing `vpn`.
+  fn area_start(&self, vpn: VirtPageNum) -> Option<VirtPageNum> {
+    self.areas
+      .range(..=vpn)
+      .next_back()
+      .filter(|(_, area)| vpn < area.vpn_range.get_end())
+      .map(|(start, _)| *start)
+  }
+
+  /// Swap out at most `budget` user pages, pages accessed since last scan
+  /// get a second chance. Return number of pages swapped out.
+  pub fn swap_out(&mut self, budget: usize) -> usize {
+    if !swap_enabled() {
+      return 0;
+    }
+    let hand = self.clock_hand;
+    let mut swapped = 0;
+    // hart sets A again only after cached translation is flushed
+    let mut cleared = false;
+    // scan pages from hand to the end, then wrap around
+    'scan: for wrapped in [false, true] {
+      for area in self.areas.values() {
+        if area.map_type != MapType::Framed
+          || !area.map_perm.contains(MapPermission::U)
+          || area.is_shared() {
+          continue;
+        }
+        for vpn in area.vpn_range {
+          if (vpn < hand) != wrapped {
+            continue;
+          }
+          match self.page_table.translate(vpn) {
+            Some(pte) if pte.is_valid() => {}
+            _ => continue,
+          }
+          if self.page_table.test_and_clear_accessed(vpn) {
+            cleared = true;
+            continue;
+          }
+          let ppn = self.page_table.translate(vpn).unwrap().ppn();
+          let slot = match swap_write(ppn.get_bytes_array()) {
+            Some(slot) => slot,
+            None => break 'scan,
+          };
+          self.page_table.mark_swapped(vpn, slot);
+          swapped += 1;
+          if swapped == budget {
+            self.clock_hand = VirtPageNum(vpn.0 + 1);
+            break 'scan;
+          }
+        }
+      }
+    }
+    if swapped > 0 || cleared {
+      self.asid.flush(None);
+    }
+    trace!("swapped out {} pages", swapped);
+    swapped
+  }
+
+  /// Bring a swapped out `vpn` back, return `None` if physical frames run out.
+  fn swap_in(&mut self, vpn: VirtPageNum) -> Option<()> {
+    if !self.is_swapped(vpn) {
+      return Some(());
+    }
+    let frame = frame_alloc()?;
+    let slot = self.page_table.translate(vpn).unwrap().swap_slot();
+    swap_read(slot, frame.ppn.get_bytes_array());
+    self.page_table.unmark_swapped(vpn, frame);
+    swap_free(slot);
+    Some(())
+  }
+
+  /// Give back swap slots held by swapped PTEs.
+  fn release_swap_slots(&mut self) {
+    for area in self.areas.values() {
+      if area.map_type != MapType::Framed {
+        continue;
+      }
+      for vpn in area.vpn_range {
+        if let Some(pte) = self.page_table.translate(vpn) {
+          if pte.is_swapped() {
+            swap_free(pte.swap_slot());
+          }
+        }
+      }
+    }
+  }
+
   /// Manually drop all Physical page the [MemorySet] holds
   /// without clean PTEs in [PageTable]
   /// # Safety
   /// [MemorySet] is invalid after calling this.
   pub unsafe fn recycle_pages(&mut self) {
+    self.release_swap_slots();
     self.areas.clear();
     self.page_table.recycle_pages();
   }
@@ -298,29 +526,40 @@ impl MemorySet {
   pub fn shrink_to(&mut self, start: VirtAddr, new_end: VirtAddr) -> bool {
     if let Some(area) = self.areas.get_mut(&start.into()) {
       area.shrink_to(&mut self.page_table, new_end.ceil());
+      self.asid.flush(None);
       true
     } else {
       false
     }
   }
 
+  /// Return `false` if no area starts at `start` or physical frames run out.
   #[allow(unused)]
   pub fn append_to(&mut self, start: VirtAddr, new_end: VirtAddr) -> bool {
     if let Some(area) = self.areas.get_mut(&start.into()) {
-      area.append_to(&mut self.page_table, new_end.ceil());
-      true
+      area.append_to(&mut self.page_table, new_end.ceil()).is_some()
     } else {
       false
     }
   }
 
-  /// Insert [`MapArea`] to current address space.
-  fn push(&mut self, mut map_area: MapArea, data: Option<&[u8]>) {
-    map_area.map(&mut self.page_table);
+  /// Insert [`MapArea`] to current address space, ELF areas are mapped lazily.
+  /// Nothing is inserted if physical frames run out.
+  fn push(&mut self, mut map_area: MapArea, data: Option<&[u8]>) -> Option<()> {
+    if map_area.elf.is_none() {
+      map_area.map(&mut self.page_table)?;
+    }
     if let Some(data) = data {
       map_area.copy_data(&mut self.page_table, data);
     }
     self.areas.insert(map_area.vpn_range.get_start(), map_area);
+    Some(())
+  }
+}
+
+impl Drop for MemorySet {
+  fn drop(&mut self) {
+    self.release_swap_slots();
   }
 }
 
@@ -344,11 +583,49 @@ bitflags! {
   }
 }
 
+/// File content of an ELF segment, loaded page by page on first access.
+#[derive(Clone)]
+struct ElfBacking {
+  data: &'static [u8],
+  start_va: VirtAddr,
+  // frames of read-only pages, shared with other address spaces
+  shared: BTreeMap<VirtPageNum, Arc<FrameTracker>>,
+}
+
+impl ElfBacking {
+  /// Copy the part of segment inside `vpn` to `ppn`, the rest is left zeroed.
+  fn fill(&self, vpn: VirtPageNum, ppn: PhysPageNum) {
+    let page_start: usize = VirtAddr::from(vpn).into();
+    let seg_start: usize = self.start_va.into();
+    let start = page_start.max(seg_start);
+    let end = (page_start + PAGE_SIZE).min(seg_start + self.data.len());
+    if start < end {
+      ppn.get_bytes_array()[start - page_start..end - page_start]
+        .copy_from_slice(&self.data[start - seg_start..end - seg_start]);
+    }
+  }
+
+  /// Find the frame of `vpn` loaded by other address spaces, or load a new one.
+  fn shared_frame(&self, vpn: VirtPageNum) -> Option<Arc<FrameTracker>> {
+    let key = (self.data.as_ptr() as usize, vpn);
+    let mut pages = SHARED_ELF_PAGES.lock();
+    if let Some(frame) = pages.get(&key).and_then(Weak::upgrade) {
+      return Some(frame);
+    }
+    let frame = Arc::new(frame_alloc()?);
+    self.fill(vpn, frame.ppn);
+    pages.retain(|_, page| page.strong_count() > 0);
+    pages.insert(key, Arc::downgrade(&frame));
+    Some(frame)
+  }
+}
+
 #[derive(Clone)]
 struct MapArea {
   vpn_range: VPNRange,
   map_type: MapType,
   map_perm: MapPermission,
+  elf: Option<ElfBacking>,
 }
 
 impl MapArea {
@@ -364,14 +641,114 @@ impl MapArea {
       vpn_range: VPNRange::new(start, end),
       map_type,
       map_perm,
+      elf: None,
     }
   }
 
+  /// Framed area backed by ELF segment `data`, which starts at `start_va`.
+  fn new_elf(
+    start_va: VirtAddr,
+    end_va: VirtAddr,
+    map_perm: MapPermission,
+    data: &'static [u8],
+  ) -> Self {
+    let mut area = Self::new(start_va, end_va, MapType::Framed, map_perm);
+    area.elf = Some(ElfBacking {
+      data,
+      start_va,
+      shared: BTreeMap::new(),
+    });
+    area
+  }
+
+  /// Read-only ELF pages are shared, never copied or swapped out.
+  fn is_shared(&self) -> bool {
+    self.elf.is_some() && !self.map_perm.contains(MapPermission::W)
+  }
+
+  /// Load `vpn` from ELF segment, return `None` if physical frames run out.
+  fn load_page(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) -> Option<()> {
+    let pte_flags = PTEFlags::from_bits(self.map_perm.bits).unwrap();
+    let shared = self.is_shared();
+    let elf = self.elf.as_mut().unwrap();
+    if shared {
+      let frame = elf.shared_frame(vpn)?;
+      page_table.map(MapArgs::builder(vpn, frame.ppn).with_flags(pte_flags))?;
+      elf.shared.insert(vpn, frame);
+      Some(())
+    } else {
+      let frame = frame_alloc()?;
+      let ppn = frame.ppn;
+      elf.fill(vpn, ppn);
+      // accessed by the fault loading it
+      page_table.map(
+        MapArgs::builder(vpn, ppn)
+          .with_flags(pte_flags | PTEFlags::A)
+          .with_frame(Some(frame)),
+      )
+    }
+  }
+
+  /// Map pages of this ELF area loaded in `src` to `dst`, shared pages keep
+  /// their frames and private pages are copied.
+  fn copy_loaded_pages(&mut self, src: &PageTable, dst: &mut PageTable) -> Option<()> {
+    let pte_flags = PTEFlags::from_bits(self.map_perm.bits).unwrap();
+    for vpn in self.vpn_range {
+      let src_pte = match src.translate(vpn) {
+        Some(pte) if pte.is_valid() || pte.is_swapped() => pte,
+        _ => continue,
+      };
+      if let Some(frame) = self.elf.as_ref().unwrap().shared.get(&vpn) {
+        dst.map(MapArgs::builder(vpn, frame.ppn).with_flags(pte_flags))?;
+        continue;
+      }
+      self.map_one(dst, vpn)?;
+      let dst_ppn = dst.translate(vpn).unwrap().ppn();
+      if src_pte.is_swapped() {
+        swap_read(src_pte.swap_slot(), dst_ppn.get_bytes_array());
+      } else {
+        dst_ppn.get_bytes_array()
+          .copy_from_slice(src_pte.ppn().get_bytes_array());
+      }
+    }
+    Some(())
+  }
+
   /// Map `self.vpn_range` to specified [`PageTable`].
-  fn map(&mut self, page_table: &mut PageTable) {
+  /// Pages mapped so far are rolled back if physical frames run out.
+  fn map(&mut self, page_table: &mut PageTable) -> Option<()> {
+    if self.map_type == MapType::Identical {
+      return self.map_identical(page_table);
+    }
     for vpn in self.vpn_range {
-      self.map_one(page_table, vpn);
+      if self.map_one(page_table, vpn).is_none() {
+        for mapped in VPNRange::new(self.vpn_range.get_start(), vpn) {
+          self.unmap_one(page_table, mapped);
+        }
+        return None;
+      }
     }
+    Some(())
+  }
+
+  /// Map identical area with the largest leaves alignment allows.
+  /// Only kernel space uses identical areas, which never recycles them.
+  fn map_identical(&mut self, page_table: &mut PageTable) -> Option<()> {
+    let pte_flags = PTEFlags::from_bits(self.map_perm.bits).unwrap();
+    let end = self.vpn_range.get_end();
+    let mut vpn = self.vpn_range.get_start();
+    while vpn < end {
+      let level = (0..3)
+        .find(|&level| vpn.0 % level_pages(level) == 0 && vpn.0 + level_pages(level) <= end.0)
+        .unwrap();
+      page_table.map(
+        MapArgs::builder(vpn, PhysPageNum(vpn.0))
+          .with_flags(pte_flags)
+          .with_level(level),
+      )?;
+      vpn = VirtPageNum(vpn.0 + level_pages(level));
+    }
+    Some(())
   }
 
   #[allow(unused)]
@@ -382,7 +759,7 @@ impl MapArea {
     }
   }
 
-  fn map_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) {
+  fn map_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) -> Option<()> {
     let (ppn, frame_tracker) = match self.map_type {
       MapType::Identical => {
         // Identical map has no need for allocating
@@ -390,7 +767,7 @@ impl MapArea {
       }
       MapType::Framed => {
         // Framed map needs to alloc new page
-        let frame = frame_alloc().unwrap();
+        let frame = frame_alloc()?;
         let ppn = frame.ppn;
         (ppn, Some(frame))
       }
@@ -400,7 +777,7 @@ impl MapArea {
       MapArgs::builder(vpn, ppn)
         .with_flags(pte_flags)
         .with_frame(frame_tracker),
-    );
+    )
   }
 
   fn unmap_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) {
@@ -408,11 +785,15 @@ impl MapArea {
       MapType::Identical => false,
       MapType::Framed => true,
     };
+    // ELF pages may not be loaded yet
     page_table.unmap(
       UnmapArgs::builder(vpn)
         .with_dealloc(free)
-        .with_panic(true),
+        .with_panic(self.elf.is_none()),
     );
+    if let Some(elf) = self.elf.as_mut() {
+      elf.shared.remove(&vpn);
+    }
   }
 
   fn shrink_to(&mut self, page_table: &mut PageTable, new_end: VirtPageNum) {
@@ -422,11 +803,35 @@ impl MapArea {
     self.vpn_range = VPNRange::new(self.vpn_range.get_start(), new_end);
   }
 
-  fn append_to(&mut self, page_table: &mut PageTable, new_end: VirtPageNum) {
-    for vpn in VPNRange::new(self.vpn_range.get_end(), new_end) {
-      self.map_one(page_table, vpn);
+  /// Area is left untouched if physical frames run out.
+  fn append_to(&mut self, page_table: &mut PageTable, new_end: VirtPageNum) -> Option<()> {
+    let old_end = self.vpn_range.get_end();
+    for vpn in VPNRange::new(old_end, new_end) {
+      if self.map_one(page_table, vpn).is_none() {
+        for mapped in VPNRange::new(old_end, vpn) {
+          self.unmap_one(page_table, mapped);
+        }
+        return None;
+      }
     }
     self.vpn_range = VPNRange::new(self.vpn_range.get_start(), new_end);
+    Some(())
+  }
+
+  /// Extend area down to `new_start`.
+  /// Area is left untouched if physical frames run out.
+  fn prepend_to(&mut self, page_table: &mut PageTable, new_start: VirtPageNum) -> Option<()> {
+    let old_start = self.vpn_range.get_start();
+    for vpn in VPNRange::new(new_start, old_start) {
+      if self.map_one(page_table, vpn).is_none() {
+        for mapped in VPNRange::new(new_start, vpn) {
+          self.unmap_one(page_table, mapped);
+        }
+        return None;
+      }
+    }
+    self.vpn_range = VPNRange::new(new_start, self.vpn_range.get_end());
+    Some(())
   }
 
   /// Copy `data` to physical addr
@@ -452,8 +857,8 @@ impl MapArea {
   }
 }
 
-#[allow(unused)]
-pub fn remap_test() {
+#[test_case]
+fn remap_test() {
   let mut kernel_space = KERNEL_SPACE.lock();
   let mid_text: VirtAddr = ((stext as usize + etext as usize) / 2).into();
   let mid_rodata: VirtAddr = ((srodata as usize + erodata as usize) / 2).into();
@@ -478,7 +883,7 @@ pub fn remap_test() {
     top,
     MapType::Framed,
     MapPermission::R,
-  ), None);
+  ), None).unwrap();
   assert_eq!(
     kernel_space.page_table.translate(bottom.into()).unwrap().is_readable(),
     true,
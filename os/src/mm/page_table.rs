@@ -1,79 +1,27 @@
 use alloc::collections::BTreeSet;
 use alloc::string::String;
 use alloc::vec::Vec;
-use bitflags::*;
-use crate::config::{PAGE_SIZE, PTE_FLAGS_BITS};
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::ops::{Deref, DerefMut};
+use kcore::page_table::RawPageTable;
+use kcore::phys_mem::Identity;
+use crate::config::PAGE_SIZE;
 use crate::mm::{
-  address::{PhysPageNum, VirtPageNum},
+  address::{PhysAddr, PhysPageAccess, PhysPageNum, VirtAddr, VirtPageNum},
   frame_allocator::{frame_alloc, FrameTracker},
-  {PhysAddr, VirtAddr},
+  swap::swap_free,
 };
-
-bitflags! {
-  pub struct PTEFlags: u16 {
-    const V = 1 << 0;
-    const R = 1 << 1;
-    const W = 1 << 2;
-    const X = 1 << 3;
-    const U = 1 << 4;
-    const G = 1 << 5;
-    const A = 1 << 6;
-    const D = 1 << 7;
-    const C = 1 << 8;
-  }
-}
-
-#[derive(Copy, Clone)]
-#[repr(C)]
-pub struct PageTableEntry {
-  pub bits: usize,
-}
-
-impl PageTableEntry {
-  pub fn new(ppn: PhysPageNum, flags: PTEFlags) -> Self {
-    Self {
-      bits: ppn.0 << PTE_FLAGS_BITS | flags.bits as usize,
-    }
-  }
-
-  pub fn empty() -> Self {
-    Self { bits: 0 }
-  }
-
-  pub fn ppn(&self) -> PhysPageNum {
-    (self.bits >> PTE_FLAGS_BITS & (1usize << 44) - 1).into()
-  }
-
-  pub fn flags(&self) -> PTEFlags {
-    PTEFlags::from_bits((self.bits & (1 << 10) - 1) as u16).unwrap()
-  }
-
-  pub fn is_valid(&self) -> bool {
-    (self.flags() & PTEFlags::V) != PTEFlags::empty()
-  }
-
-  pub fn is_readable(&self) -> bool {
-    (self.flags() & PTEFlags::R) != PTEFlags::empty()
-  }
-
-  pub fn is_writable(&self) -> bool {
-    (self.flags() & PTEFlags::W) != PTEFlags::empty()
-  }
-
-  pub fn is_executable(&self) -> bool {
-    (self.flags() & PTEFlags::X) != PTEFlags::empty()
-  }
-
-  pub fn is_cow_page(&self) -> bool {
-    (self.flags() & PTEFlags::C) != PTEFlags::empty()
-  }
-}
+pub use kcore::page_table::{level_pages, PTEFlags, PageTableEntry};
+use crate::task::{exit, fault_in_page, PinnedPages, KILLED_XCODE};
 
 pub struct MapArgs {
   vpn: VirtPageNum,
   ppn: PhysPageNum,
   flags: PTEFlags,
   frame: Option<FrameTracker>,
+  // level of leaf entry, 1 for 2 MiB and 0 for 1 GiB pages
+  level: usize,
 }
 
 pub struct UnmapArgs {
@@ -89,6 +37,7 @@ impl MapArgs {
       ppn,
       flags: PTEFlags::empty(),
       frame: None,
+      level: 2,
     }
   }
 
@@ -113,6 +62,11 @@ impl MapArgs {
     self.frame = frame;
     self
   }
+
+  pub fn with_level(mut self, level: usize) -> Self {
+    self.level = level;
+    self
+  }
 }
 
 impl UnmapArgs {
@@ -142,121 +96,177 @@ impl UnmapArgs {
 }
 
 pub struct PageTable {
-  root_ppn: PhysPageNum,
+  raw: RawPageTable<Identity>,
   frames_holder: BTreeSet<FrameTracker>,
+  // size of frames_holder, read by other harts without lock
+  frames: AtomicUsize,
 }
 
 impl PageTable {
-  pub fn new() -> Self {
-    let frame = frame_alloc().unwrap();
-    Self {
-      root_ppn: frame.ppn,
+  /// Return `None` if there is no frame left for the root page table.
+  pub fn new() -> Option<Self> {
+    let frame = frame_alloc()?;
+    Some(Self {
+      raw: RawPageTable::new(frame.ppn, Identity),
       frames_holder: {
         let mut b = BTreeSet::new();
         b.insert(frame);
         b
       },
-    }
+      frames: AtomicUsize::new(1),
+    })
   }
 
   pub fn from_token(satp: usize) -> Self {
     Self {
-      root_ppn: satp.into(),
+      raw: RawPageTable::new(satp.into(), Identity),
       frames_holder: BTreeSet::new(),
+      frames: AtomicUsize::new(0),
     }
   }
 
   pub fn token(&self) -> usize {
-    8usize << 60 | self.root_ppn.0
+    self.raw.token()
   }
 
+  /// PPN of returned entry is the 4 KiB page of `vpn`, even in a huge page.
   pub fn translate(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
-    self.find_pte(vpn).map(|pte| *pte)
+    self.raw.translate(vpn)
   }
 
   pub fn translate_va(&self, va: VirtAddr) -> Option<PhysAddr> {
-    self.find_ppn(va.clone().floor()).map(|ppn| {
-      (PhysAddr::from(ppn).0 + va.page_offset()).into()
-    })
+    self.raw.translate_va(va)
   }
 
-  pub fn map(&mut self, args: MapArgs) {
-    let MapArgs { vpn, ppn, flags, mut frame } = args;
-    let pte = self.find_pte_create(vpn).unwrap();
-    assert!(!pte.is_valid(), "vpn {:?} is mapped but should not", vpn);
-
-    // update pte permission
-    *pte = PageTableEntry::new(ppn, flags | PTEFlags::V);
+  pub fn find_ppn(&self, vpn: VirtPageNum) -> Option<PhysPageNum> {
+    self.raw.find_ppn(vpn)
+  }
+
+  /// Map `args.vpn` to `args.ppn`, return `None` if frames for
+  /// intermediate page tables run out.
+  pub fn map(&mut self, args: MapArgs) -> Option<()> {
+    let MapArgs { vpn, ppn, flags, mut frame, level } = args;
+    let frames_holder = &mut self.frames_holder;
+    let mapped = self.raw.map(vpn, ppn, flags, level, || {
+      let new_frame = frame_alloc()?;
+      let ppn = new_frame.ppn;
+      frames_holder.insert(new_frame);
+      Some(ppn)
+    });
     // hold this page
-    if let Some(ft) = frame.take() {
+    if let Some(ft) = frame.take().filter(|_| mapped.is_some()) {
       assert_eq!(ppn, ft.ppn, "map: ppn and frame.ppn should equal");
       self.frames_holder.insert(ft);
     }
+    self.sync_frames();
+    mapped
   }
 
   pub fn unmap(&mut self, args: UnmapArgs) {
     let vpn = args.vpn;
-    let pte = match self.find_pte(vpn) {
-      Some(pte) if pte.is_valid() => pte,
-      _ => if args.panic {
+    match self.raw.unmap(vpn) {
+      Some(pte) if pte.is_swapped() => swap_free(pte.swap_slot()),
+      Some(pte) => if args.dealloc {
+        let key_to_remove = FrameTracker { ppn: pte.ppn() };
+        self.frames_holder.remove(&key_to_remove);
+        core::mem::forget(key_to_remove);
+        self.sync_frames();
+      }
+      None => if args.panic {
         panic!("vpn {:?} should mapped but not", vpn);
-      } else {
-        return;
       }
-    };
-    *pte = PageTableEntry::empty();
-    if args.dealloc {
-      let key_to_remove = FrameTracker { ppn: pte.ppn() };
-      self.frames_holder.remove(&key_to_remove);
-      core::mem::forget(key_to_remove);
     }
   }
 
+  /// Clear accessed bit of a valid `vpn`, return whether it was set.
+  pub fn test_and_clear_accessed(&mut self, vpn: VirtPageNum) -> bool {
+    match self.raw.find_pte(vpn) {
+      Some(pte) if pte.is_valid() => {
+        let accessed = (pte.flags() & PTEFlags::A) != PTEFlags::empty();
+        pte.bits &= !(PTEFlags::A.bits() as usize);
+        accessed
+      }
+      _ => false,
+    }
+  }
+
+  /// Replace the mapping of `vpn` with a swapped PTE pointing to `slot`,
+  /// frame behind it is released.
+  pub fn mark_swapped(&mut self, vpn: VirtPageNum, slot: usize) {
+    let pte = self.raw.find_pte(vpn).unwrap();
+    assert!(pte.is_valid(), "vpn {:?} should mapped but not", vpn);
+    let key_to_remove = FrameTracker { ppn: pte.ppn() };
+    *pte = PageTableEntry::new_swapped(slot, pte.flags());
+    self.frames_holder.remove(&key_to_remove);
+    core::mem::forget(key_to_remove);
+    self.sync_frames();
+  }
+
+  /// Map a swapped `vpn` to `frame` with its former permission,
+  /// return the slot it was in. Page is marked accessed, so that the
+  /// clock does not evict it before its faulting access.
+  pub fn unmark_swapped(&mut self, vpn: VirtPageNum, frame: FrameTracker) -> usize {
+    let pte = self.raw.find_pte(vpn).unwrap();
+    assert!(pte.is_swapped(), "vpn {:?} should swapped but not", vpn);
+    let slot = pte.swap_slot();
+    *pte = PageTableEntry::new(frame.ppn, (pte.flags() - PTEFlags::S) | PTEFlags::V | PTEFlags::A);
+    self.frames_holder.insert(frame);
+    self.sync_frames();
+    slot
+  }
+
+  /// Number of frames held by this [PageTable], including frames of
+  /// page table itself. Other harts may read it while this one maps.
+  pub fn frames_count(&self) -> usize {
+    self.frames.load(Ordering::Relaxed)
+  }
+
+  fn sync_frames(&self) {
+    self.frames.store(self.frames_holder.len(), Ordering::Relaxed);
+  }
+
   /// Manually drop all Physical page the [PageTable] holds
   /// without clean PTEs in [PageTable]
   /// # Safety
   /// [PageTable] is invalid after calling this.
   pub unsafe fn recycle_pages(&mut self) {
     self.frames_holder.clear();
+    self.sync_frames();
   }
 }
 
-impl PageTable {
-  /// Create a new VA to PA, create a map if not exist but not alloc actual page.
-  fn find_pte_create(&mut self, vpn: VirtPageNum) -> Option<&mut PageTableEntry> {
-    let index = vpn.indexes();
-    let mut ppn = self.root_ppn.clone();
-    let mut ret = None;
-    for (i, idx) in index.into_iter().enumerate() {
-      let next_pte = &mut ppn.get_pte_array()[idx];
-      if !next_pte.is_valid() && i != 2 {
-        let new_frame = frame_alloc().unwrap();
-        *next_pte = PageTableEntry::new(new_frame.ppn, PTEFlags::V);
-        self.frames_holder.insert(new_frame);
-      }
-      ppn = next_pte.ppn();
-      ret = Some(next_pte);
-    }
-    ret
-  }
+/// Bring `vpn` of current task in as a page fault from user would, so
+/// that kernel could access it through physical address. Return `false`
+/// if `vpn` is not mapped for user.
+/// Caller must not hold any lock or reference of current task.
+fn ensure_resident(page_table: &PageTable, vpn: VirtPageNum) -> bool {
+  let resident = || page_table.translate(vpn)
+    .map_or(false, |pte| pte.is_valid() && pte.flags().contains(PTEFlags::U));
+  if !resident() && !fault_in_page(vpn) {
+    // current task is chosen by OOM killer
+    exit(KILLED_XCODE);
+  }
+  resident()
+}
 
-  fn find_pte(&self, vpn: VirtPageNum) -> Option<&mut PageTableEntry> {
-    let index = vpn.indexes();
-    let mut ppn = self.root_ppn.clone();
-    let mut ret = None;
-    for (i, idx) in index.into_iter().enumerate() {
-      let next_pte = &mut ppn.get_pte_array()[idx];
-      if !next_pte.is_valid() && i != 2 {
-        return None;
-      }
-      ppn = next_pte.ppn();
-      ret = Some(next_pte);
-    }
-    ret
+/// User memory accessed through physical addresses, pages behind it are
+/// not swapped out until it is dropped.
+pub struct UserBuffer {
+  buffers: Vec<&'static mut [u8]>,
+  _pin: PinnedPages,
+}
+
+impl Deref for UserBuffer {
+  type Target = [&'static mut [u8]];
+
+  fn deref(&self) -> &Self::Target {
+    &self.buffers
   }
+}
 
-  pub fn find_ppn(&self, vpn: VirtPageNum) -> Option<PhysPageNum> {
-    self.find_pte(vpn).map(|pte| pte.ppn())
+impl DerefMut for UserBuffer {
+  fn deref_mut(&mut self) -> &mut Self::Target {
+    &mut self.buffers
   }
 }
 
@@ -264,54 +274,77 @@ pub fn translated_byte_buffer(
   page_table_token: usize,
   va_ptr: *const u8,
   len: usize,
-) -> Vec<&'static mut [u8]> {
+) -> Option<UserBuffer> {
+  // pages faulted in first stay while later ones fault in
+  let pin = PinnedPages::current();
   let page_table = PageTable::from_token(page_table_token);
   let mut len_to_find = len;
   let mut cur_va = va_ptr as usize;
   let mut ret = Vec::with_capacity(len / PAGE_SIZE + 1);
   while len_to_find > 0 {
     let va = VirtAddr::from(cur_va);
-    // TODO: fix malicious input
-    let ppn = page_table.find_ppn(va.floor()).unwrap();
+    if !ensure_resident(&page_table, va.floor()) {
+      return None;
+    }
+    let ppn = page_table.find_ppn(va.floor())?;
     let cur_len = PAGE_SIZE.min(len_to_find.min(PAGE_SIZE - va.page_offset()));
     ret.push(&mut ppn.get_bytes_array()[va.page_offset()..va.page_offset() + cur_len]);
     len_to_find -= cur_len;
     cur_va += cur_len;
   }
-  ret
+  Some(UserBuffer { buffers: ret, _pin: pin })
 }
 
-pub fn translated_str(page_table_token: usize, va_ptr: *const u8) -> String {
+/// Kernel pointer to user byte at `va`, `None` if it is not mapped for user.
+fn translated_byte(page_table: &PageTable, va: usize) -> Option<*mut u8> {
+  let va = VirtAddr::from(va);
+  if !ensure_resident(page_table, va.floor()) {
+    return None;
+  }
+  page_table.translate_va(va).map(|pa| pa.0 as *mut u8)
+}
+
+/// Copy a NUL terminated string from user space, `None` if it is not
+/// mapped for user. A string longer than `max_len` is cut after
+/// `max_len + 1` bytes, so that caller could tell it is too long.
+pub fn translated_str(page_table_token: usize, va_ptr: *const u8, max_len: usize) -> Option<String> {
   let page_table = PageTable::from_token(page_table_token);
   let mut ret = String::new();
   let mut va = va_ptr as usize;
-  // TODO: performance & security
-  loop {
-    let ch = *(page_table.translate_va(va.into())).unwrap().get_mut::<u8>();
+  while ret.len() <= max_len {
+    let ch = unsafe { *translated_byte(&page_table, va)? };
     if ch == 0 {
       break;
-    } else {
-      ret.push(ch as char);
-      va += 1;
+    }
+    ret.push(ch as char);
+    va += 1;
+  }
+  Some(ret)
+}
+
+/// Copy data the `va_ptr` points to from user space to kernel space,
+/// `None` if it is not mapped for user.
+pub fn translated_copyin<T: Copy>(token: usize, va_ptr: *const T) -> Option<T> {
+  let page_table = PageTable::from_token(token);
+  let mut val = MaybeUninit::<T>::uninit();
+  let dst = val.as_mut_ptr() as *mut u8;
+  for i in 0..core::mem::size_of::<T>() {
+    unsafe {
+      *dst.add(i) = *translated_byte(&page_table, va_ptr as usize + i)?;
     }
   }
-  ret
+  Some(unsafe { val.assume_init() })
 }
 
-/// Copy data `val` from kernel space to user space the `va_ptr` points to.
-pub fn translated_copyout<T>(token: usize, va_ptr: *mut T, val: T) {
+/// Copy data `val` from kernel space to user space the `va_ptr` points to,
+/// `None` if it is not mapped for user, bytes before the bad one are copied.
+pub fn translated_copyout<T>(token: usize, va_ptr: *mut T, val: T) -> Option<()> {
   let page_table = PageTable::from_token(token);
-  let mut src = &val as *const T as *mut u8;
-  let mut dst_va = va_ptr as usize;
-  for _ in 0..core::mem::size_of::<T>() {
+  let src = &val as *const T as *const u8;
+  for i in 0..core::mem::size_of::<T>() {
     unsafe {
-      let dst = page_table
-        .translate_va(VirtAddr::from(dst_va))
-        .unwrap()
-        .0 as *mut u8;
-      *dst = *src;
-      src = src.add(1);
-      dst_va = (dst_va as *const u8).add(1) as usize;
+      *translated_byte(&page_table, va_ptr as usize + i)? = *src.add(i);
     }
   }
+  Some(())
 }
@@ -0,0 +1,357 @@
+//! Object caches serving small allocations of kernel heap.
+//!
+//! Objects of a cache are carved from slabs taken from buddy heap. Each
+//! hart keeps a magazine of free objects per cache, so that most
+//! allocations and frees take no lock.
+
+use core::alloc::Layout;
+use core::cell::UnsafeCell;
+use core::mem::size_of;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(test)]
+use log::trace;
+use spin::Mutex;
+use crate::common::cpuid;
+use crate::config::MAX_CPU_NUM;
+use crate::mm::heap_allocator::{alloc_slab, dealloc_slab};
+
+// object size of each cache, larger allocations go to buddy heap
+const OBJECT_SIZES: [usize; CACHE_NUM] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+const CACHE_NUM: usize = 8;
+pub const SLAB_SIZE: usize = 0x4000;
+const MAGAZINE_SIZE: usize = 32;
+// empty slabs each cache keeps instead of returning to buddy heap
+const SPARE_SLABS: usize = 1;
+#[cfg(feature = "slab_poison")]
+const POISON_FREE: u8 = 0x6b;
+#[cfg(feature = "slab_poison")]
+const POISON_ALLOC: u8 = 0xa5;
+
+/// Header at the start of each slab.
+struct Slab {
+  free: *mut FreeObject,
+  in_use: usize,
+  prev: *mut Slab,
+  next: *mut Slab,
+}
+
+struct FreeObject {
+  next: *mut FreeObject,
+}
+
+/// Slabs of a cache, shared by all harts.
+struct SlabCache {
+  object_size: usize,
+  // slabs with free objects, including empty ones
+  partial: *mut Slab,
+  empty_slabs: usize,
+}
+
+// slabs are only touched with the cache locked
+unsafe impl Send for SlabCache {}
+
+/// Free objects cached by a hart.
+struct Magazine {
+  objects: [*mut u8; MAGAZINE_SIZE],
+  len: usize,
+}
+
+struct Magazines([[UnsafeCell<Magazine>; CACHE_NUM]; MAX_CPU_NUM]);
+
+// each hart only touches its own magazines
+unsafe impl Sync for Magazines {}
+
+struct Counters {
+  allocs: AtomicUsize,
+  frees: AtomicUsize,
+  refills: AtomicUsize,
+  flushes: AtomicUsize,
+  slabs: AtomicUsize,
+}
+
+/// Counters of a cache.
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+  pub object_size: usize,
+  pub allocs: usize,
+  pub frees: usize,
+  /// magazines refilled from slabs
+  pub refills: usize,
+  /// magazines flushed back to slabs
+  pub flushes: usize,
+  pub slabs: usize,
+}
+
+const EMPTY_MAGAZINE: UnsafeCell<Magazine> = UnsafeCell::new(Magazine {
+  objects: [null_mut(); MAGAZINE_SIZE],
+  len: 0,
+});
+const EMPTY_MAGAZINES: [UnsafeCell<Magazine>; CACHE_NUM] = [EMPTY_MAGAZINE; CACHE_NUM];
+static MAGAZINES: Magazines = Magazines([EMPTY_MAGAZINES; MAX_CPU_NUM]);
+
+static CACHES: [Mutex<SlabCache>; CACHE_NUM] = {
+  const fn cache(object_size: usize) -> Mutex<SlabCache> {
+    Mutex::new(SlabCache {
+      object_size,
+      partial: null_mut(),
+      empty_slabs: 0,
+    })
+  }
+  [
+    cache(OBJECT_SIZES[0]), cache(OBJECT_SIZES[1]), cache(OBJECT_SIZES[2]), cache(OBJECT_SIZES[3]),
+    cache(OBJECT_SIZES[4]), cache(OBJECT_SIZES[5]), cache(OBJECT_SIZES[6]), cache(OBJECT_SIZES[7]),
+  ]
+};
+
+const NO_COUNTERS: Counters = Counters {
+  allocs: AtomicUsize::new(0),
+  frees: AtomicUsize::new(0),
+  refills: AtomicUsize::new(0),
+  flushes: AtomicUsize::new(0),
+  slabs: AtomicUsize::new(0),
+};
+static COUNTERS: [Counters; CACHE_NUM] = [NO_COUNTERS; CACHE_NUM];
+
+impl SlabCache {
+  /// Move objects into `magazine` until it is half full, return `false`
+  /// if no object could be taken.
+  fn refill(&mut self, magazine: &mut Magazine, counters: &Counters) -> bool {
+    while magazine.len < MAGAZINE_SIZE / 2 {
+      match self.pop(counters) {
+        Some(object) => {
+          magazine.objects[magazine.len] = object;
+          magazine.len += 1;
+        }
+        None => break,
+      }
+    }
+    magazine.len > 0
+  }
+
+  /// Move `count` objects from `magazine` back to their slabs.
+  fn flush(&mut self, magazine: &mut Magazine, count: usize, counters: &Counters) {
+    for _ in 0..count {
+      magazine.len -= 1;
+      self.push(magazine.objects[magazine.len], counters);
+    }
+  }
+
+  fn pop(&mut self, counters: &Counters) -> Option<*mut u8> {
+    if self.partial.is_null() {
+      self.grow(counters)?;
+    }
+    unsafe {
+      let slab = &mut *self.partial;
+      let object = slab.free;
+      slab.free = (*object).next;
+      if slab.in_use == 0 {
+        self.empty_slabs -= 1;
+      }
+      slab.in_use += 1;
+      if slab.free.is_null() {
+        self.unlink(slab);
+      }
+      #[cfg(feature = "slab_poison")]
+      core::ptr::write_bytes(object as *mut u8, POISON_FREE, size_of::<FreeObject>());
+      Some(object as *mut u8)
+    }
+  }
+
+  fn push(&mut self, object: *mut u8, counters: &Counters) {
+    unsafe {
+      let slab = &mut *((object as usize & !(SLAB_SIZE - 1)) as *mut Slab);
+      let object = object as *mut FreeObject;
+      if slab.free.is_null() {
+        self.link(slab);
+      }
+      (*object).next = slab.free;
+      slab.free = object;
+      slab.in_use -= 1;
+      if slab.in_use == 0 {
+        if self.empty_slabs < SPARE_SLABS {
+          self.empty_slabs += 1;
+        } else {
+          self.unlink(slab);
+          dealloc_slab(slab as *mut Slab as *mut u8);
+          counters.slabs.fetch_sub(1, Ordering::Relaxed);
+        }
+      }
+    }
+  }
+
+  /// Take a new slab from buddy heap.
+  fn grow(&mut self, counters: &Counters) -> Option<()> {
+    let base = alloc_slab()?;
+    let size = self.object_size;
+    // first object follows header
+    let first = (size_of::<Slab>() + size - 1) / size * size;
+    unsafe {
+      #[cfg(feature = "slab_poison")]
+      core::ptr::write_bytes(base.add(first), POISON_FREE, SLAB_SIZE - first);
+      let slab = &mut *(base as *mut Slab);
+      slab.free = null_mut();
+      slab.in_use = 0;
+      for offset in (first..SLAB_SIZE - size + 1).step_by(size).rev() {
+        let object = base.add(offset) as *mut FreeObject;
+        (*object).next = slab.free;
+        slab.free = object;
+      }
+      self.link(slab);
+    }
+    self.empty_slabs += 1;
+    counters.slabs.fetch_add(1, Ordering::Relaxed);
+    Some(())
+  }
+
+  /// Return empty slabs to buddy heap, return number of slabs freed.
+  fn shrink(&mut self, counters: &Counters) -> usize {
+    let mut freed = 0;
+    let mut slab = self.partial;
+    while !slab.is_null() {
+      unsafe {
+        let next = (*slab).next;
+        if (*slab).in_use == 0 {
+          self.unlink(&mut *slab);
+          dealloc_slab(slab as *mut u8);
+          freed += 1;
+        }
+        slab = next;
+      }
+    }
+    self.empty_slabs = 0;
+    counters.slabs.fetch_sub(freed, Ordering::Relaxed);
+    freed
+  }
+
+  fn link(&mut self, slab: &mut Slab) {
+    slab.prev = null_mut();
+    slab.next = self.partial;
+    if !self.partial.is_null() {
+      unsafe { (*self.partial).prev = slab };
+    }
+    self.partial = slab;
+  }
+
+  fn unlink(&mut self, slab: &mut Slab) {
+    unsafe {
+      if slab.prev.is_null() {
+        self.partial = slab.next;
+      } else {
+        (*slab.prev).next = slab.next;
+      }
+      if !slab.next.is_null() {
+        (*slab.next).prev = slab.prev;
+      }
+    }
+  }
+}
+
+fn magazine(index: usize) -> &'static mut Magazine {
+  unsafe { &mut *MAGAZINES.0[cpuid()][index].get() }
+}
+
+/// Cache serving `layout`, `None` if it is too large for any cache.
+pub fn cache_index(layout: &Layout) -> Option<usize> {
+  let size = layout.size().max(layout.align()).next_power_of_two();
+  OBJECT_SIZES.iter().position(|&object_size| object_size >= size)
+}
+
+/// Allocate an object from cache `index`, return null if buddy heap is
+/// out of memory.
+pub fn alloc(index: usize) -> *mut u8 {
+  let magazine = magazine(index);
+  if magazine.len == 0 {
+    if !CACHES[index].lock().refill(magazine, &COUNTERS[index]) {
+      return null_mut();
+    }
+    COUNTERS[index].refills.fetch_add(1, Ordering::Relaxed);
+  }
+  magazine.len -= 1;
+  let object = magazine.objects[magazine.len];
+  COUNTERS[index].allocs.fetch_add(1, Ordering::Relaxed);
+  #[cfg(feature = "slab_poison")]
+  check_poison(index, object);
+  object
+}
+
+pub fn dealloc(index: usize, object: *mut u8) {
+  #[cfg(feature = "slab_poison")]
+  unsafe {
+    core::ptr::write_bytes(object, POISON_FREE, OBJECT_SIZES[index]);
+  }
+  let magazine = magazine(index);
+  if magazine.len == MAGAZINE_SIZE {
+    CACHES[index].lock().flush(magazine, MAGAZINE_SIZE / 2, &COUNTERS[index]);
+    COUNTERS[index].flushes.fetch_add(1, Ordering::Relaxed);
+  }
+  magazine.objects[magazine.len] = object;
+  magazine.len += 1;
+  COUNTERS[index].frees.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Panic if a free object was written, then fill it to catch reads of
+/// uninitialized memory.
+#[cfg(feature = "slab_poison")]
+fn check_poison(index: usize, object: *mut u8) {
+  let size = OBJECT_SIZES[index];
+  let bytes = unsafe { core::slice::from_raw_parts_mut(object, size) };
+  if let Some(offset) = bytes.iter().position(|&b| b != POISON_FREE) {
+    panic!(
+      "slab: object {:#x} of cache {} written after free at offset {:#x}",
+      object as usize, size, offset,
+    );
+  }
+  bytes.fill(POISON_ALLOC);
+}
+
+/// Return empty slabs of every cache to buddy heap, objects held by
+/// magazines are kept. Return number of slabs freed.
+pub fn shrink_caches() -> usize {
+  (0..CACHE_NUM)
+    .map(|index| CACHES[index].lock().shrink(&COUNTERS[index]))
+    .sum()
+}
+
+pub fn cache_stats(index: usize) -> CacheStats {
+  let counters = &COUNTERS[index];
+  CacheStats {
+    object_size: OBJECT_SIZES[index],
+    allocs: counters.allocs.load(Ordering::Relaxed),
+    frees: counters.frees.load(Ordering::Relaxed),
+    refills: counters.refills.load(Ordering::Relaxed),
+    flushes: counters.flushes.load(Ordering::Relaxed),
+    slabs: counters.slabs.load(Ordering::Relaxed),
+  }
+}
+
+#[test_case]
+fn slab_test() {
+  use alloc::boxed::Box;
+  use alloc::vec::Vec;
+  let index = cache_index(&Layout::new::<[u8; 100]>()).unwrap();
+  assert_eq!(OBJECT_SIZES[index], 128);
+  let before = cache_stats(index);
+  let a = Box::new([1u8; 100]);
+  let addr = a.as_ptr() as usize;
+  assert_eq!(addr % 128, 0);
+  drop(a);
+  // magazine is last in first out
+  let b = Box::new([2u8; 100]);
+  assert_eq!(b.as_ptr() as usize, addr);
+  drop(b);
+  // overflow magazine and slab
+  let v: Vec<Box<[u8; 100]>> = (0..SLAB_SIZE / 128 * 2).map(|_| Box::new([3u8; 100])).collect();
+  assert!(v.iter().all(|b| b.iter().all(|&x| x == 3)));
+  drop(v);
+  let after = cache_stats(index);
+  assert_eq!(after.allocs - before.allocs, after.frees - before.frees);
+  for index in 0..CACHE_NUM {
+    let stats = cache_stats(index);
+    trace!(
+      "slab-{}: allocs {} frees {} refills {} flushes {} slabs {}",
+      stats.object_size, stats.allocs, stats.frees, stats.refills, stats.flushes, stats.slabs,
+    );
+  }
+  trace!("slab_test passed!");
+}
@@ -0,0 +1,75 @@
+use alloc::sync::Arc;
+use kcore::index_allocator::IndexAllocator;
+use lazy_static::lazy_static;
+use log::info;
+use crate::config::{PAGE_SIZE, SWAP_PAGES};
+use crate::drivers::{block_device, BlockDevice, BLOCK_SIZE};
+use crate::sync::SpinMutex;
+
+const BLOCKS_PER_PAGE: usize = PAGE_SIZE / BLOCK_SIZE;
+
+struct SwapSpace {
+  device: Arc<dyn BlockDevice>,
+  // page sized slots, allocated the same way as frames
+  slots: IndexAllocator,
+}
+
+impl SwapSpace {
+  fn read(&self, slot: usize, buf: &mut [u8]) {
+    for (i, block) in buf.chunks_mut(BLOCK_SIZE).enumerate() {
+      self.device.read_block(slot * BLOCKS_PER_PAGE + i, block);
+    }
+  }
+
+  fn write(&self, slot: usize, buf: &[u8]) {
+    for (i, block) in buf.chunks(BLOCK_SIZE).enumerate() {
+      self.device.write_block(slot * BLOCKS_PER_PAGE + i, block);
+    }
+  }
+}
+
+lazy_static! {
+  static ref SWAP_SPACE: SpinMutex<Option<SwapSpace>> = SpinMutex::new(None);
+}
+
+pub fn init_swap() {
+  match block_device() {
+    Some(device) => {
+      *SWAP_SPACE.lock() = Some(SwapSpace {
+        device,
+        slots: IndexAllocator::new(0, SWAP_PAGES),
+      });
+      info!("swap enabled with {} slots", SWAP_PAGES);
+    }
+    None => info!("no block device, swap disabled"),
+  }
+}
+
+pub fn swap_enabled() -> bool {
+  SWAP_SPACE.lock().is_some()
+}
+
+/// Write a page to a free slot, return `None` if there is no slot left.
+pub fn swap_write(page: &[u8]) -> Option<usize> {
+  let mut swap = SWAP_SPACE.lock();
+  let swap = swap.as_mut()?;
+  let slot = swap.slots.alloc()?;
+  swap.write(slot, page);
+  Some(slot)
+}
+
+/// Read a page from `slot`, the slot is still in use after reading.
+pub fn swap_read(slot: usize, page: &mut [u8]) {
+  SWAP_SPACE.lock()
+    .as_ref()
+    .expect("swap_read: swap is disabled")
+    .read(slot, page);
+}
+
+pub fn swap_free(slot: usize) {
+  SWAP_SPACE.lock()
+    .as_mut()
+    .expect("swap_free: swap is disabled")
+    .slots
+    .dealloc(slot);
+}
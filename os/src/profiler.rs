@@ -0,0 +1,123 @@
+//! Sampling profiler, timer interrupts record the interrupted pc of user
+//! and kernel into per-hart buffers. Kernel takes timer interrupts only
+//! while it runs.
+//!
+//! Dump lines are `S <pc> <count> <symbol>` for kernel and `U <pc> <count>`
+//! for user samples, one histogram per hart sorted by pc.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+use crate::common::cpuid;
+use crate::config::MAX_CPU_NUM;
+use crate::println;
+use crate::stack_trace::lookup_symbol;
+
+const MAX_SAMPLES: usize = 4096;
+// pc is 2 bytes aligned, lowest bit of a sample is set for user pc
+const USER_SAMPLE: usize = 1;
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static SAMPLES: [HartSamples; MAX_CPU_NUM] = [EMPTY_SAMPLES; MAX_CPU_NUM];
+// dump sorts buffers in place, one at a time
+static DUMPING: Mutex<()> = Mutex::new(());
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SAMPLES: HartSamples = HartSamples::new();
+
+/// Written only by its own hart, from timer interrupts.
+struct HartSamples {
+  pcs: UnsafeCell<[usize; MAX_SAMPLES]>,
+  len: AtomicUsize,
+  dropped: AtomicUsize,
+}
+
+unsafe impl Sync for HartSamples {}
+
+impl HartSamples {
+  const fn new() -> Self {
+    Self {
+      pcs: UnsafeCell::new([0; MAX_SAMPLES]),
+      len: AtomicUsize::new(0),
+      dropped: AtomicUsize::new(0),
+    }
+  }
+}
+
+pub fn is_running() -> bool {
+  RUNNING.load(Ordering::Acquire)
+}
+
+/// Drop samples taken before and start sampling.
+pub fn start() {
+  for samples in SAMPLES.iter() {
+    samples.len.store(0, Ordering::Release);
+    samples.dropped.store(0, Ordering::Relaxed);
+  }
+  RUNNING.store(true, Ordering::Release);
+}
+
+pub fn stop() {
+  RUNNING.store(false, Ordering::Release);
+}
+
+pub fn has_samples() -> bool {
+  SAMPLES.iter().any(|samples| samples.len.load(Ordering::Acquire) > 0)
+}
+
+/// Record `pc` interrupted by timer on current hart.
+pub fn sample(pc: usize, user: bool) {
+  if !is_running() {
+    return;
+  }
+  let samples = &SAMPLES[cpuid()];
+  let len = samples.len.load(Ordering::Relaxed);
+  if len == MAX_SAMPLES {
+    samples.dropped.fetch_add(1, Ordering::Relaxed);
+    return;
+  }
+  unsafe {
+    (*samples.pcs.get())[len] = if user { pc | USER_SAMPLE } else { pc };
+  }
+  samples.len.store(len + 1, Ordering::Release);
+}
+
+/// Print histogram of samples of every hart, nothing if another dump is
+/// in progress. Harts still sampling only append past what is printed.
+pub fn dump() {
+  let _guard = match DUMPING.try_lock() {
+    Some(guard) => guard,
+    None => return,
+  };
+  println!("== Begin profile ==");
+  for (hartid, samples) in SAMPLES.iter().enumerate() {
+    let len = samples.len.load(Ordering::Acquire);
+    if len == 0 {
+      continue;
+    }
+    println!(
+      "hart {}: {} samples, {} dropped",
+      hartid, len, samples.dropped.load(Ordering::Relaxed),
+    );
+    let pcs = unsafe { &mut (&mut *samples.pcs.get())[..len] };
+    pcs.sort_unstable();
+    let mut start = 0;
+    while start < len {
+      let sample = pcs[start];
+      let count = pcs[start..].iter().take_while(|&&pc| pc == sample).count();
+      print_sample(sample, count);
+      start += count;
+    }
+  }
+  println!("== End profile ==");
+}
+
+fn print_sample(sample: usize, count: usize) {
+  if sample & USER_SAMPLE != 0 {
+    println!("U {:#x} {}", sample & !USER_SAMPLE, count);
+  } else if let Some((name, offset)) = lookup_symbol(sample) {
+    println!("S {:#x} {} {}+{:#x}", sample, count, name, offset);
+  } else {
+    println!("S {:#x} {} ?", sample, count);
+  }
+}
@@ -1,7 +1,14 @@
 #![allow(unused)]
 
 use core::arch::asm;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use log::info;
+use crate::common::cpuid;
+use crate::config::{MAX_CPU_NUM, VIRT_TEST};
+use crate::dtb::has_test_device;
 
+// legacy extensions, used when firmware lacks the new ones
 const SBI_SET_TIMER: usize = 0;
 const SBI_CONSOLE_PUTCHAR: usize = 1;
 const SBI_CONSOLE_GETCHAR: usize = 2;
@@ -12,7 +19,125 @@ const SBI_REMOTE_SFENCE_VMA: usize = 6;
 const SBI_REMOTE_SFENCE_VMA_ASID: usize = 7;
 const SBI_SHUTDOWN: usize = 8;
 
+// sifive_test device of QEMU virt machine, exit code in upper half
+const VIRT_TEST_FAIL: u32 = 0x3333;
+const VIRT_TEST_PASS: u32 = 0x5555;
+
+// extension IDs
+const EID_BASE: usize = 0x10;
+const EID_TIME: usize = 0x5449_4d45;
+const EID_IPI: usize = 0x73_5049;
+const EID_RFENCE: usize = 0x5246_4e43;
+const EID_HSM: usize = 0x48_534d;
+const EID_SRST: usize = 0x5352_5354;
+const EID_DBCN: usize = 0x4442_434e;
+
+// BASE functions
+const BASE_GET_SPEC_VERSION: usize = 0;
+const BASE_GET_IMPL_ID: usize = 1;
+const BASE_GET_IMPL_VERSION: usize = 2;
+const BASE_PROBE_EXTENSION: usize = 3;
+
+// functions of other extensions
+const TIME_SET_TIMER: usize = 0;
+const HSM_HART_START: usize = 0;
+const HSM_HART_STOP: usize = 1;
+const HSM_HART_GET_STATUS: usize = 2;
+const HSM_HART_SUSPEND: usize = 3;
+const IPI_SEND_IPI: usize = 0;
+const RFENCE_REMOTE_SFENCE_VMA_ASID: usize = 2;
+const SRST_SYSTEM_RESET: usize = 0;
+const DBCN_CONSOLE_READ: usize = 1;
+const DBCN_CONSOLE_WRITE_BYTE: usize = 2;
+
+// SRST reset types and reasons
+const RESET_TYPE_SHUTDOWN: usize = 0;
+const RESET_REASON_NONE: usize = 0;
+const RESET_REASON_FAILURE: usize = 1;
+
+// HSM hart states
+pub const HART_STARTED: usize = 0;
+pub const HART_STOPPED: usize = 1;
+pub const HART_START_PENDING: usize = 2;
+pub const HART_STOP_PENDING: usize = 3;
+pub const HART_SUSPENDED: usize = 4;
+
+// HSM suspend types
+const SUSPEND_DEFAULT_RETENTIVE: usize = 0;
+
+pub const SBI_SUCCESS: isize = 0;
+pub const SBI_ERR_FAILED: isize = -1;
+pub const SBI_ERR_NOT_SUPPORTED: isize = -2;
+pub const SBI_ERR_INVALID_PARAM: isize = -3;
+pub const SBI_ERR_DENIED: isize = -4;
+pub const SBI_ERR_INVALID_ADDRESS: isize = -5;
+pub const SBI_ERR_ALREADY_AVAILABLE: isize = -6;
+pub const SBI_ERR_ALREADY_STARTED: isize = -7;
+pub const SBI_ERR_ALREADY_STOPPED: isize = -8;
+
+/// Extensions found by [`init`], each one takes a bit.
+#[derive(Copy, Clone)]
+#[repr(usize)]
+pub enum Extension {
+  Base,
+  Time,
+  Ipi,
+  Rfence,
+  Hsm,
+  Srst,
+  Dbcn,
+}
+
+const EXTENSIONS: [(Extension, usize, &str); 7] = [
+  (Extension::Base, EID_BASE, "BASE"),
+  (Extension::Time, EID_TIME, "TIME"),
+  (Extension::Ipi, EID_IPI, "IPI"),
+  (Extension::Rfence, EID_RFENCE, "RFENCE"),
+  (Extension::Hsm, EID_HSM, "HSM"),
+  (Extension::Srst, EID_SRST, "SRST"),
+  (Extension::Dbcn, EID_DBCN, "DBCN"),
+];
+
+static PROBED: AtomicUsize = AtomicUsize::new(0);
+
+// bytes read by DBCN, firmware needs physical address
+static mut DBCN_READ_BUF: [u8; MAX_CPU_NUM] = [0; MAX_CPU_NUM];
+
+pub struct SbiRet {
+  pub error: isize,
+  pub value: usize,
+}
+
+impl SbiRet {
+  pub fn is_ok(&self) -> bool {
+    self.error == SBI_SUCCESS
+  }
+}
+
+/// Call function `fid` of extension `eid`.
+fn sbi_ecall(eid: usize, fid: usize, args: [usize; 5]) -> SbiRet {
+  let error: isize;
+  let value: usize;
+  unsafe {
+    asm!(
+    "ecall",
+    inlateout("x10") args[0] => error,
+    inlateout("x11") args[1] => value,
+    in("x12") args[2],
+    in("x13") args[3],
+    in("x14") args[4],
+    in("x16") fid,
+    in("x17") eid,
+    );
+  }
+  SbiRet { error, value }
+}
+
 pub fn sbi_call(which: usize, arg0: usize, arg1: usize, arg2: usize) -> usize {
+  sbi_call4(which, arg0, arg1, arg2, 0)
+}
+
+fn sbi_call4(which: usize, arg0: usize, arg1: usize, arg2: usize, arg3: usize) -> usize {
   let mut ret;
   unsafe {
     asm!(
@@ -20,25 +145,145 @@ pub fn sbi_call(which: usize, arg0: usize, arg1: usize, arg2: usize) -> usize {
     inlateout("x10") arg0 => ret,
     in("x11") arg1,
     in("x12") arg2,
+    in("x13") arg3,
     in("x17") which,
     );
   }
   ret
 }
 
+/// Probe extensions, legacy calls are used until this is done.
+/// Must be called after .bss is cleared.
+pub fn init() {
+  // firmware without BASE only knows legacy calls
+  if !sbi_ecall(EID_BASE, BASE_PROBE_EXTENSION, [EID_BASE, 0, 0, 0, 0]).is_ok() {
+    return;
+  }
+  let mut probed = 0;
+  for (ext, eid, _) in EXTENSIONS {
+    let ret = sbi_ecall(EID_BASE, BASE_PROBE_EXTENSION, [eid, 0, 0, 0, 0]);
+    if ret.is_ok() && ret.value != 0 {
+      probed |= 1 << ext as usize;
+    }
+  }
+  PROBED.store(probed, Ordering::Release);
+}
+
+/// Log firmware version and extensions found by [`init`].
+pub fn print_info() {
+  if !has_extension(Extension::Base) {
+    info!("SBI: legacy only");
+    return;
+  }
+  let spec = sbi_ecall(EID_BASE, BASE_GET_SPEC_VERSION, [0; 5]).value;
+  let impl_id = sbi_ecall(EID_BASE, BASE_GET_IMPL_ID, [0; 5]).value;
+  let impl_version = sbi_ecall(EID_BASE, BASE_GET_IMPL_VERSION, [0; 5]).value;
+  info!(
+    "SBI: spec v{}.{}, impl id {} version {:#x}",
+    spec >> 24 & 0x7f, spec & 0xff_ffff, impl_id, impl_version,
+  );
+  for (ext, _, name) in EXTENSIONS {
+    info!("SBI: {:<6} {}", name, if has_extension(ext) { "yes" } else { "legacy" });
+  }
+}
+
+pub fn has_extension(ext: Extension) -> bool {
+  PROBED.load(Ordering::Acquire) & 1 << ext as usize != 0
+}
+
+/// Return 0 if there is no input.
 pub fn console_getchar() -> usize {
-  sbi_call(SBI_CONSOLE_GETCHAR, 0, 0, 0)
+  if !has_extension(Extension::Dbcn) {
+    return sbi_call(SBI_CONSOLE_GETCHAR, 0, 0, 0);
+  }
+  let buf = unsafe { addr_of_mut!(DBCN_READ_BUF[cpuid()]) };
+  let ret = sbi_ecall(EID_DBCN, DBCN_CONSOLE_READ, [1, buf as usize, 0, 0, 0]);
+  if ret.is_ok() && ret.value == 1 {
+    unsafe { buf.read_volatile() as usize }
+  } else {
+    0
+  }
 }
 
 pub fn console_putchar(c: usize) {
-  sbi_call(SBI_CONSOLE_PUTCHAR, c, 0, 0);
+  if has_extension(Extension::Dbcn) {
+    sbi_ecall(EID_DBCN, DBCN_CONSOLE_WRITE_BYTE, [c, 0, 0, 0, 0]);
+  } else {
+    sbi_call(SBI_CONSOLE_PUTCHAR, c, 0, 0);
+  }
 }
 
-pub fn shutdown() -> ! {
-  sbi_call(SBI_SHUTDOWN, 0, 0, 0);
+/// Power off, `failure` is passed as reset reason so that QEMU exits
+/// with an error status.
+pub fn shutdown(failure: bool) -> ! {
+  if has_extension(Extension::Srst) {
+    let reason = if failure { RESET_REASON_FAILURE } else { RESET_REASON_NONE };
+    sbi_ecall(EID_SRST, SRST_SYSTEM_RESET, [RESET_TYPE_SHUTDOWN, reason, 0, 0, 0]);
+  } else {
+    // legacy shutdown has no exit status, try test device of QEMU first
+    if has_test_device() {
+      let status = if failure { VIRT_TEST_FAIL | 1 << 16 } else { VIRT_TEST_PASS };
+      unsafe {
+        (VIRT_TEST as *mut u32).write_volatile(status);
+      }
+    }
+    sbi_call(SBI_SHUTDOWN, 0, 0, 0);
+  }
   panic!("It should shutdown!");
 }
 
 pub fn set_timer(timer: usize) {
-  sbi_call(SBI_SET_TIMER, timer, 0, 0);
+  if has_extension(Extension::Time) {
+    sbi_ecall(EID_TIME, TIME_SET_TIMER, [timer, 0, 0, 0, 0]);
+  } else {
+    sbi_call(SBI_SET_TIMER, timer, 0, 0);
+  }
+}
+
+/// Send supervisor software interrupt to harts in `hart_mask`.
+pub fn send_ipi(hart_mask: usize) {
+  if has_extension(Extension::Ipi) {
+    sbi_ecall(EID_IPI, IPI_SEND_IPI, [hart_mask, 0, 0, 0, 0]);
+  } else {
+    sbi_call(SBI_SEND_IPI, &hart_mask as *const usize as usize, 0, 0);
+  }
+}
+
+/// Start `hartid` at physical `start_addr` with `a1` set to `opaque`.
+pub fn hart_start(hartid: usize, start_addr: usize, opaque: usize) -> SbiRet {
+  sbi_ecall(EID_HSM, HSM_HART_START, [hartid, start_addr, opaque, 0, 0])
+}
+
+/// Stop current hart, only returns on failure.
+pub fn hart_stop() -> SbiRet {
+  sbi_ecall(EID_HSM, HSM_HART_STOP, [0; 5])
+}
+
+pub fn hart_get_status(hartid: usize) -> SbiRet {
+  sbi_ecall(EID_HSM, HSM_HART_GET_STATUS, [hartid, 0, 0, 0, 0])
+}
+
+/// Suspend current hart until an enabled interrupt is pending.
+pub fn hart_suspend() -> SbiRet {
+  sbi_ecall(EID_HSM, HSM_HART_SUSPEND, [SUSPEND_DEFAULT_RETENTIVE, 0, 0, 0, 0])
+}
+
+/// Flush TLB entries of `asid` in `[start, start + size)` on harts in
+/// `hart_mask`, returns after they are flushed.
+pub fn remote_sfence_vma_asid(hart_mask: usize, start: usize, size: usize, asid: usize) {
+  if has_extension(Extension::Rfence) {
+    sbi_ecall(
+      EID_RFENCE,
+      RFENCE_REMOTE_SFENCE_VMA_ASID,
+      [hart_mask, 0, start, size, asid],
+    );
+  } else {
+    sbi_call4(
+      SBI_REMOTE_SFENCE_VMA_ASID,
+      &hart_mask as *const usize as usize,
+      start,
+      size,
+      asid,
+    );
+  }
 }
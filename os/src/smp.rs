@@ -0,0 +1,194 @@
+//! Start, stop and suspend secondary harts.
+
+use core::arch::asm;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use log::{info, warn};
+use crate::common::{cpuid, intr_off};
+use crate::config::{BOOT_STACK_SIZE, CLOCK_FREQ, MAX_CPU_NUM, PAGE_SIZE};
+use crate::sbi::{self, has_extension, Extension, HART_STARTED, HART_STOPPED};
+use crate::timer::get_time;
+use crate::vars::*;
+
+// harts running kernel
+static ONLINE_HARTS: AtomicUsize = AtomicUsize::new(0);
+// harts asked to stop when they are back in scheduler
+static STOP_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+// hart reporting a panic, other harts park once they notice
+static PANIC_HART: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Boot stack `[bottom, top)` of `hartid`, with a guard page below bottom.
+pub fn boot_stack_position(hartid: usize) -> (usize, usize) {
+  let top = boot_stack_lower_bound as usize + (hartid + 1) * (BOOT_STACK_SIZE + PAGE_SIZE);
+  (top - BOOT_STACK_SIZE, top)
+}
+
+/// `[bottom, top)` of the boot stack holding `addr`.
+pub fn boot_stack_bounds(addr: usize) -> Option<(usize, usize)> {
+  (0..MAX_CPU_NUM)
+    .map(boot_stack_position)
+    .find(|&(bottom, top)| (bottom..top).contains(&addr))
+}
+
+/// Hart whose boot stack guard page holds `addr`.
+pub fn boot_stack_guard_hart(addr: usize) -> Option<usize> {
+  (0..MAX_CPU_NUM).find(|&hartid| {
+    let (bottom, _) = boot_stack_position(hartid);
+    (bottom - PAGE_SIZE..bottom).contains(&addr)
+  })
+}
+
+/// Mark current hart running kernel.
+pub fn set_online() {
+  ONLINE_HARTS.fetch_or(1 << cpuid(), Ordering::AcqRel);
+}
+
+pub fn is_online(hartid: usize) -> bool {
+  ONLINE_HARTS.load(Ordering::Acquire) & 1 << hartid != 0
+}
+
+pub fn online_harts() -> usize {
+  ONLINE_HARTS.load(Ordering::Acquire)
+}
+
+/// Start every hart other than the boot hart.
+pub fn start_secondary_harts() {
+  for hartid in (0..MAX_CPU_NUM).filter(|&hartid| hartid != cpuid()) {
+    start_hart(hartid);
+  }
+}
+
+/// Start a stopped `hartid`, return `false` if it does not exist or is running.
+pub fn start_hart(hartid: usize) -> bool {
+  if hartid >= MAX_CPU_NUM || is_online(hartid) {
+    return false;
+  }
+  let (_, stack_top) = boot_stack_position(hartid);
+  if has_extension(Extension::Hsm) {
+    let status = sbi::hart_get_status(hartid);
+    if !status.is_ok() {
+      // no such hart
+      return false;
+    }
+    match status.value {
+      HART_STOPPED => {
+        let ret = sbi::hart_start(hartid, _secondary_start as usize, stack_top);
+        if !ret.is_ok() {
+          warn!("[kernel] failed to start hart {}, error {}", hartid, ret.error);
+        }
+        return ret.is_ok();
+      }
+      // parked in entry.asm by firmware starting all harts
+      HART_STARTED => {}
+      _ => return false,
+    }
+  }
+  // release the hart parked in entry.asm, and wake it from wfi
+  let release = (hart_release as usize as *const AtomicUsize).wrapping_add(hartid);
+  unsafe {
+    (*release).store(stack_top, Ordering::Release);
+  }
+  sbi::send_ipi(1 << hartid);
+  true
+}
+
+/// Ask `hartid` to stop once it gets back to scheduler.
+pub fn stop_hart(hartid: usize) -> bool {
+  if hartid >= MAX_CPU_NUM || !is_online(hartid) {
+    return false;
+  }
+  STOP_REQUESTS.fetch_or(1 << hartid, Ordering::AcqRel);
+  sbi::send_ipi(1 << hartid);
+  true
+}
+
+pub fn stop_requested() -> bool {
+  STOP_REQUESTS.load(Ordering::Acquire) & 1 << cpuid() != 0 || other_hart_panicking()
+}
+
+/// Stop current hart if it is asked to, must be called without any task.
+pub fn handle_stop_request() {
+  park_if_panicking();
+  if !stop_requested() {
+    return;
+  }
+  let hartid = cpuid();
+  intr_off();
+  ONLINE_HARTS.fetch_and(!(1 << hartid), Ordering::AcqRel);
+  STOP_REQUESTS.fetch_and(!(1 << hartid), Ordering::AcqRel);
+  info!("hart {} stopped", hartid);
+  if has_extension(Extension::Hsm) {
+    sbi::hart_stop();
+  }
+  // firmware can not stop it, park it until started again
+  unsafe {
+    asm!("mv a0, {}", "j _park_hart", in(reg) hartid, options(noreturn));
+  }
+}
+
+fn other_hart_panicking() -> bool {
+  let panic_hart = PANIC_HART.load(Ordering::Acquire);
+  panic_hart != usize::MAX && panic_hart != cpuid()
+}
+
+/// Claim panic report for current hart and stop other harts, return
+/// hart which has claimed it before. Harts spinning in kernel may not
+/// notice in time, they are waited for a short while only.
+pub fn begin_panic() -> Option<usize> {
+  let hartid = cpuid();
+  if let Err(panic_hart) =
+    PANIC_HART.compare_exchange(usize::MAX, hartid, Ordering::AcqRel, Ordering::Acquire) {
+    return Some(panic_hart);
+  }
+  let others = ONLINE_HARTS.load(Ordering::Acquire) & !(1 << hartid);
+  sbi::send_ipi(others);
+  let deadline = get_time() + CLOCK_FREQ / 10;
+  while ONLINE_HARTS.load(Ordering::Acquire) & others != 0 && get_time() < deadline {
+    spin_loop();
+  }
+  None
+}
+
+/// Park current hart for good if another hart is reporting a panic.
+pub fn park_if_panicking() {
+  if !other_hart_panicking() {
+    return;
+  }
+  intr_off();
+  ONLINE_HARTS.fetch_and(!(1 << cpuid()), Ordering::AcqRel);
+  loop {
+    unsafe {
+      asm!("wfi");
+    }
+  }
+}
+
+/// Wait for an interrupt, using HSM retentive suspend if possible.
+pub fn suspend() {
+  if !has_extension(Extension::Hsm) || !sbi::hart_suspend().is_ok() {
+    unsafe {
+      asm!("wfi");
+    }
+  }
+}
+
+/// Stop other harts and power off, harts still busy after a second
+/// are left running.
+pub fn shutdown(failure: bool) -> ! {
+  let hartid = cpuid();
+  for other in (0..MAX_CPU_NUM).filter(|&other| other != hartid) {
+    stop_hart(other);
+  }
+  let deadline = get_time() + CLOCK_FREQ;
+  while ONLINE_HARTS.load(Ordering::Acquire) & !(1 << hartid) != 0 {
+    if get_time() > deadline {
+      warn!(
+        "[kernel] harts {:#b} did not stop before shutdown",
+        ONLINE_HARTS.load(Ordering::Acquire) & !(1 << hartid),
+      );
+      break;
+    }
+    spin_loop();
+  }
+  sbi::shutdown(failure)
+}
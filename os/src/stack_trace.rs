@@ -1,19 +1,109 @@
-use core::arch::asm;
+use core::arch::{asm, global_asm};
+use core::slice;
+use core::str;
 use crate::println;
+use crate::smp::boot_stack_bounds;
+use crate::task::kernel_stack_bounds;
+use crate::trap::trap_stack_bounds;
+use crate::vars::{etext, stext};
+
+// symbol table generated by build.rs, empty until kernel is linked again
+global_asm!(include_str!("ksyms.S"));
+
+extern "C" {
+  fn _ksyms_num();
+  fn _ksyms_addrs();
+  fn _ksyms_offsets();
+  fn _ksyms_names();
+}
+
+/// Function holding `pc` and offset of `pc` from its start.
+pub fn lookup_symbol(pc: usize) -> Option<(&'static str, usize)> {
+  if !(stext as usize..etext as usize).contains(&pc) {
+    return None;
+  }
+  let (addrs, offsets) = unsafe {
+    let num = (_ksyms_num as usize as *const usize).read_volatile();
+    (
+      slice::from_raw_parts(_ksyms_addrs as usize as *const usize, num),
+      slice::from_raw_parts(_ksyms_offsets as usize as *const u32, num + 1),
+    )
+  };
+  let index = match addrs.binary_search(&pc) {
+    Ok(index) => index,
+    Err(0) => return None,
+    Err(index) => index - 1,
+  };
+  let name = unsafe {
+    let start = (_ksyms_names as usize as *const u8).add(offsets[index] as usize);
+    let len = (offsets[index + 1] - offsets[index]) as usize;
+    str::from_utf8_unchecked(slice::from_raw_parts(start, len))
+  };
+  Some((name, pc - addrs[index]))
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum StackKind {
+  Kernel,
+  Boot,
+  Trap,
+}
+
+#[derive(Copy, Clone)]
+struct Stack {
+  bottom: usize,
+  top: usize,
+  kind: StackKind,
+}
+
+impl Stack {
+  /// Find the stack holding frame record below `fp`.
+  fn of_frame(fp: usize) -> Option<Self> {
+    let record = fp.checked_sub(16)?;
+    let (kind, (bottom, top)) = trap_stack_bounds(record)
+      .map(|bounds| (StackKind::Trap, bounds))
+      .or_else(|| boot_stack_bounds(record).map(|bounds| (StackKind::Boot, bounds)))
+      .or_else(|| kernel_stack_bounds(record).map(|bounds| (StackKind::Kernel, bounds)))?;
+    let stack = Self { bottom, top, kind };
+    stack.holds(fp).then_some(stack)
+  }
+
+  fn holds(&self, fp: usize) -> bool {
+    fp % 8 == 0 && self.bottom + 16 <= fp && fp <= self.top
+  }
+}
 
 pub unsafe fn print_stack_trace() {
-  let mut cur_fp: usize;
-  // load riscv64 stack pointer
-  asm!("mv {}, fp", out(reg) cur_fp);
-  let mut cnt = 0;
+  let fp: usize;
+  // load riscv64 frame pointer
+  asm!("mv {}, fp", out(reg) fp);
+  print_stack_trace_from(fp);
+}
+
+/// Walk frame records from `fp` while they stay in the same stack, only
+/// trap stack may lead to the faulting stack.
+pub fn print_stack_trace_from(mut fp: usize) {
   println!("== Begin stack trace ==");
-  while cur_fp != 0 {
-    unsafe {
-      let ra = *((cur_fp - 8) as *const usize);
-      cur_fp = *((cur_fp - 16) as *const usize);
-      println!("{}: ra=0x{:016x}, fp=0x{:016x}", cnt, ra, cur_fp);
+  let mut stack = Stack::of_frame(fp);
+  let mut depth = 0;
+  while let Some(current) = stack {
+    let (ra, caller_fp) = unsafe {
+      (*((fp - 8) as *const usize), *((fp - 16) as *const usize))
+    };
+    if let Some((name, offset)) = lookup_symbol(ra) {
+      println!("{:2}: {:#018x} {}+{:#x}", depth, ra, name, offset);
+    } else {
+      println!("{:2}: {:#018x} ?", depth, ra);
     }
-    cnt += 1;
+    depth += 1;
+    stack = if current.holds(caller_fp) && caller_fp > fp {
+      Some(current)
+    } else if current.kind == StackKind::Trap {
+      Stack::of_frame(caller_fp).filter(|stack| stack.kind != StackKind::Trap)
+    } else {
+      None
+    };
+    fp = caller_fp;
   }
   println!("== End stack trace ==");
 }
@@ -4,6 +4,7 @@ use core::ops::{Deref, DerefMut};
 use core::sync::atomic::AtomicBool;
 use core::sync::atomic::Ordering::{Acquire, Relaxed, Release};
 use crate::common::{pop_off, push_off, r_tp};
+use crate::smp::park_if_panicking;
 
 pub struct SpinLock(AtomicBool, Cell<isize>);
 
@@ -21,7 +22,10 @@ impl SpinLock {
     if self.holding() {
       panic!("SpinLock was locked by current thread");
     }
-    while let Err(_) = self.0.compare_exchange(false, true, Acquire, Relaxed) {}
+    while let Err(_) = self.0.compare_exchange(false, true, Acquire, Relaxed) {
+      // holder may have been stopped by a panic
+      park_if_panicking();
+    }
     self.1.set(r_tp() as isize);
   }
 
@@ -75,7 +79,9 @@ impl<T> SpinMutex<T> {
     if self.holding() {
       panic!("SpinMutex was locked by current thread");
     }
-    while let Err(_) = self.futex.compare_exchange(false, true, Acquire, Relaxed) {}
+    while let Err(_) = self.futex.compare_exchange(false, true, Acquire, Relaxed) {
+      park_if_panicking();
+    }
     self.cpu.set(r_tp() as isize);
     unsafe {
       SpinMutexGuard::new(self)
@@ -1,5 +1,9 @@
 mod up;
 mod lock;
+mod sleep;
 
 pub use up::UPSafeCell;
 pub use lock::{SpinLock, SpinMutex, SpinMutexGuard};
+pub use sleep::SleepMutex;
+#[allow(unused)]
+pub use sleep::WaitQueue;
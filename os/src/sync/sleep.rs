@@ -0,0 +1,131 @@
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use crate::sync::{SpinMutex, SpinMutexGuard};
+use crate::task::{block_current, get_current_task, might_sleep, wake_up, TaskControlBlock};
+
+/// Tasks sleeping until an event, woken in the order they slept.
+pub struct WaitQueue {
+  tasks: SpinMutex<VecDeque<Arc<TaskControlBlock>>>,
+}
+
+impl WaitQueue {
+  pub const fn new() -> Self {
+    Self {
+      tasks: SpinMutex::new(VecDeque::new()),
+    }
+  }
+
+  /// Sleep until woken. `guard` protects the condition waited for, it is
+  /// released once current task is queued, so that a waker taking the same
+  /// lock does not miss current task. Caller must hold no other spin lock.
+  /// A killed task returns without being woken.
+  pub fn sleep<T: ?Sized>(&self, guard: SpinMutexGuard<'_, T>) {
+    let task = get_current_task();
+    let ptr = Arc::as_ptr(&task);
+    task.lock();
+    self.tasks.lock().push_back(Arc::clone(&task));
+    drop(guard);
+    block_current(task);
+    // still queued if not woken from here
+    self.tasks.lock().retain(|task| Arc::as_ptr(task) != ptr);
+  }
+
+  /// Wake the task sleeping longest, return `false` if there is none.
+  pub fn wake_one(&self) -> bool {
+    loop {
+      // taken out first, a sleeping task is locked before this queue
+      let task = match self.tasks.lock().pop_front() {
+        Some(task) => task,
+        None => return false,
+      };
+      // a killed sleeper is not blocked, try the next one
+      if wake_up(task) {
+        return true;
+      }
+    }
+  }
+
+  /// Wake every sleeping task, return how many were woken.
+  #[allow(unused)]
+  pub fn wake_all(&self) -> usize {
+    let tasks = core::mem::take(&mut *self.tasks.lock());
+    tasks.into_iter().filter(|task| wake_up(Arc::clone(task))).count()
+  }
+}
+
+/// Mutex whose waiters sleep instead of spinning, for critical sections
+/// long enough to stall a hart. Only tasks holding no spin lock may take it.
+/// Waiting is not interrupted by kill, a killed waiter yields until it
+/// gets the lock.
+pub struct SleepMutex<T: ?Sized> {
+  locked: SpinMutex<bool>,
+  waiters: WaitQueue,
+  data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for SleepMutex<T> {}
+
+unsafe impl<T: ?Sized + Send> Sync for SleepMutex<T> {}
+
+pub struct SleepMutexGuard<'a, T: ?Sized + 'a> {
+  lock: &'a SleepMutex<T>,
+}
+
+impl<T> SleepMutex<T> {
+  pub const fn new(t: T) -> Self {
+    Self {
+      locked: SpinMutex::new(false),
+      waiters: WaitQueue::new(),
+      data: UnsafeCell::new(t),
+    }
+  }
+}
+
+impl<T: ?Sized> SleepMutex<T> {
+  /// Current task sleeps while another task holds the lock.
+  pub fn lock(&self) -> SleepMutexGuard<'_, T> {
+    might_sleep();
+    loop {
+      let mut locked = self.locked.lock();
+      if !*locked {
+        *locked = true;
+        return SleepMutexGuard { lock: self };
+      }
+      self.waiters.sleep(locked);
+    }
+  }
+
+  #[allow(unused)]
+  pub fn try_lock(&self) -> Option<SleepMutexGuard<'_, T>> {
+    let mut locked = self.locked.lock();
+    if *locked {
+      return None;
+    }
+    *locked = true;
+    Some(SleepMutexGuard { lock: self })
+  }
+}
+
+impl<T: ?Sized> Deref for SleepMutexGuard<'_, T> {
+  type Target = T;
+
+  fn deref(&self) -> &Self::Target {
+    unsafe { &*self.lock.data.get() }
+  }
+}
+
+impl<T: ?Sized> DerefMut for SleepMutexGuard<'_, T> {
+  fn deref_mut(&mut self) -> &mut Self::Target {
+    unsafe { &mut *self.lock.data.get() }
+  }
+}
+
+impl<T: ?Sized> Drop for SleepMutexGuard<'_, T> {
+  fn drop(&mut self) {
+    *self.lock.locked.lock() = false;
+    // woken task takes the lock again, or sleeps if another task was first
+    self.lock.waiters.wake_one();
+  }
+}
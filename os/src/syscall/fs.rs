@@ -1,11 +1,28 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
 use crate::mm::translated_byte_buffer;
 use crate::print;
 use crate::sbi::console_getchar;
-use crate::task::{get_current_token, yield_};
+use crate::sync::SleepMutex;
+use crate::task::{exit_if_killed, get_current_task, get_current_token, yield_};
+use super::{EFAULT, EINVAL};
 
 const FD_STDIN: usize = 0;
 const FD_STDOUT: usize = 1;
 
+const CAPTURE_OFF: usize = 0;
+const CAPTURE_ON: usize = 1;
+const CAPTURE_READ: usize = 2;
+// newest bytes kept, older ones are dropped
+const CAPTURE_SIZE: usize = 4096;
+
+lazy_static! {
+  // stdout of every capturing task, in the order written
+  static ref CAPTURED: SleepMutex<VecDeque<u8>> =
+    SleepMutex::new(VecDeque::with_capacity(CAPTURE_SIZE));
+}
+
 pub fn sys_read(fd: usize, buf: *const u8, len: usize) -> isize {
   match fd {
     FD_STDIN => {
@@ -15,13 +32,17 @@ pub fn sys_read(fd: usize, buf: *const u8, len: usize) -> isize {
         c = console_getchar();
         if c == 0 {
           yield_();
+          exit_if_killed();
           continue;
         } else {
           break;
         }
       }
       let ch = c as u8;
-      let mut buffers = translated_byte_buffer(get_current_token(), buf, len);
+      let mut buffers = match translated_byte_buffer(get_current_token(), buf, len) {
+        Some(buffers) => buffers,
+        None => return -EFAULT,
+      };
       unsafe {
         buffers[0].as_mut_ptr().write_volatile(ch);
       }
@@ -36,10 +57,17 @@ pub fn sys_read(fd: usize, buf: *const u8, len: usize) -> isize {
 pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
   match fd {
     FD_STDOUT => {
-      let buffers = translated_byte_buffer(get_current_token(), buf, len);
-      for buffer in buffers {
+      let capture = get_current_task().inner_borrow_ptr().capture;
+      let buffers = match translated_byte_buffer(get_current_token(), buf, len) {
+        Some(buffers) => buffers,
+        None => return -EFAULT,
+      };
+      for buffer in buffers.iter() {
         // TODO: fix malicious input
         print!("{}", core::str::from_utf8(buffer).unwrap());
+        if capture {
+          capture_output(buffer);
+        }
       }
       len as isize
     }
@@ -48,3 +76,44 @@ pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
     }
   }
 }
+
+fn capture_output(bytes: &[u8]) {
+  let mut captured = CAPTURED.lock();
+  for &byte in bytes {
+    if captured.len() == CAPTURE_SIZE {
+      captured.pop_front();
+    }
+    captured.push_back(byte);
+  }
+}
+
+/// Collect stdout of current task and tasks it forks or execs, besides
+/// printing it. `cmd` 0 stops and 1 starts capturing, 2 moves at most
+/// `len` bytes of what every task captured to `buf` and returns the count.
+pub fn sys_capture(cmd: usize, buf: *mut u8, len: usize) -> isize {
+  match cmd {
+    CAPTURE_OFF | CAPTURE_ON => {
+      get_current_task().inner_borrow_ptr_mut().capture = cmd == CAPTURE_ON;
+      0
+    }
+    CAPTURE_READ => {
+      // user buffer may fault in, not under lock
+      let bytes: Vec<u8> = {
+        let mut captured = CAPTURED.lock();
+        let count = len.min(captured.len());
+        captured.drain(..count).collect()
+      };
+      let mut buffers = match translated_byte_buffer(get_current_token(), buf, bytes.len()) {
+        Some(buffers) => buffers,
+        None => return -EFAULT,
+      };
+      let mut copied = 0;
+      for buffer in buffers.iter_mut() {
+        buffer.copy_from_slice(&bytes[copied..copied + buffer.len()]);
+        copied += buffer.len();
+      }
+      copied as isize
+    }
+    _ => -EINVAL,
+  }
+}
@@ -1,15 +1,21 @@
 mod fs;
+mod syslog;
 mod process;
+mod trace;
 
 use log::error;
 use fs::*;
+use syslog::*;
 use process::*;
-use crate::task::exit;
+use trace::traced_syscall;
+use crate::task::{exit, get_current_tcb_ref, TraceMode};
 
 const SYSCALL_READ: usize = 63;
 const SYSCALL_WRITE: usize = 64;
 const SYSCALL_EXIT: usize = 93;
+const SYSCALL_SYSLOG: usize = 116;
 const SYSCALL_YIELD: usize = 124;
+const SYSCALL_KILL: usize = 129;
 const SYSCALL_GET_TIME: usize = 169;
 const SYSCALL_GETPID: usize = 172;
 const SYSCALL_FORK: usize = 220;
@@ -17,23 +23,46 @@ const SYSCALL_EXEC: usize = 221;
 const SYSCALL_SBRK: usize = 214;
 const SYSCALL_WAITPID: usize = 260;
 const SYSCALL_GET_TASKINFO: usize = 114514;
+const SYSCALL_SET_TRACE: usize = 114515;
+const SYSCALL_PROFILE: usize = 114516;
+const SYSCALL_CAPTURE: usize = 114517;
+
+// errno
+const EPERM: isize = 1;
+const ESRCH: isize = 3;
+const E2BIG: isize = 7;
+const ENOMEM: isize = 12;
+const EFAULT: isize = 14;
+const EINVAL: isize = 22;
 
 // TODO: performance: may replace with a syscall table
 //  `match` slows down function select
 
 pub fn syscall(which: usize, args: [usize; 3]) -> isize {
+  match get_current_tcb_ref().inner_borrow_ptr().trace {
+    TraceMode::Off => dispatch(which, args),
+    mode => traced_syscall(which, args, mode),
+  }
+}
+
+fn dispatch(which: usize, args: [usize; 3]) -> isize {
   match which {
     SYSCALL_READ => sys_read(args[0], args[1] as *const u8, args[2]),
     SYSCALL_WRITE => sys_write(args[0], args[1] as *const u8, args[2]),
     SYSCALL_EXIT => sys_exit(args[0] as i32),
+    SYSCALL_SYSLOG => sys_syslog(args[0], args[1] as *mut u8, args[2]),
     SYSCALL_YIELD => sys_yield(),
+    SYSCALL_KILL => sys_kill(args[0]),
     SYSCALL_GET_TIME => sys_get_time(),
     SYSCALL_GETPID => sys_getpid(),
     SYSCALL_FORK => sys_fork(),
-    SYSCALL_EXEC => sys_exec(args[0] as *const u8),
+    SYSCALL_EXEC => sys_exec(args[0] as *const u8, args[1] as *const usize),
     SYSCALL_SBRK => sys_sbrk(args[0] as i32),
     SYSCALL_WAITPID => sys_waitpid(args[0] as isize, args[1] as *mut i32),
     SYSCALL_GET_TASKINFO => sys_get_taskinfo(),
+    SYSCALL_SET_TRACE => sys_set_trace(args[0]),
+    SYSCALL_PROFILE => sys_profile(args[0]),
+    SYSCALL_CAPTURE => sys_capture(args[0], args[1] as *mut u8, args[2]),
     _ => {
       error!("Unsupported syscall: {}", which);
       exit(-1)
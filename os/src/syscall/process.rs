@@ -1,6 +1,10 @@
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use crate::config::PAGE_SIZE;
 use crate::loader::get_app_data_by_name;
-use crate::mm::{translated_str, translated_copyout};
+use crate::profiler;
+use crate::mm::{translated_str, translated_copyin, translated_copyout};
 use crate::task::{
   get_current_task,
   yield_,
@@ -9,15 +13,26 @@ use crate::task::{
   get_current_token,
   change_program_brk,
   add_task,
+  kill,
+  with_oom_retry,
+  TraceMode,
 };
 use crate::timer::get_time_ms;
+use super::{E2BIG, EFAULT, EINVAL, ENOMEM, ESRCH};
+
+// leave most of user stack to the program
+const MAX_ARG_SIZE: usize = PAGE_SIZE;
 
 pub fn sys_getpid() -> isize {
   get_current_pid()
 }
 
 pub fn sys_fork() -> isize {
-  let forking_task = get_current_task().fork();
+  let task = get_current_task();
+  let forking_task = match with_oom_retry(|| task.fork()) {
+    Some(task) => task,
+    None => return -ENOMEM,
+  };
   let child_pid = forking_task.pid.0;
   let forking_task_inner = forking_task.inner_borrow_ptr();
   let trap_cx = forking_task_inner.get_trap_cx();
@@ -29,12 +44,44 @@ pub fn sys_fork() -> isize {
   child_pid as isize
 }
 
-pub fn sys_exec(path: *const u8) -> isize {
+/// `args` is a null terminated array of strings, `path` is the only
+/// argument if it is null. Return `argc` on success.
+pub fn sys_exec(path: *const u8, mut args: *const usize) -> isize {
   let token = get_current_token();
-  let path = translated_str(token, path);
+  let path = match translated_str(token, path, MAX_ARG_SIZE) {
+    Some(path) => path,
+    None => return -EFAULT,
+  };
+  let mut argv = Vec::new();
+  if args.is_null() {
+    argv.push(path.clone());
+  } else {
+    // pointers and strings are put on user stack, stop once they do not fit
+    let mut size = size_of::<usize>();
+    loop {
+      let arg = match translated_copyin(token, args) {
+        Some(0) => break,
+        Some(arg) => arg,
+        None => return -EFAULT,
+      };
+      let arg = match translated_str(token, arg as *const u8, MAX_ARG_SIZE - size) {
+        Some(arg) => arg,
+        None => return -EFAULT,
+      };
+      size += size_of::<usize>() + arg.len() + 1;
+      if size > MAX_ARG_SIZE {
+        return -E2BIG;
+      }
+      argv.push(arg);
+      args = args.wrapping_add(1);
+    }
+  }
   if let Some(data) = get_app_data_by_name(path.as_str()) {
-    get_current_task().exec(data);
-    0
+    let task = get_current_task();
+    match with_oom_retry(|| task.exec(data, &argv)) {
+      Some(_) => argv.len() as isize,
+      None => -ENOMEM,
+    }
   } else {
     -1
   }
@@ -69,14 +116,18 @@ pub fn sys_waitpid(pid: isize, xcode_ptr: *mut i32) -> isize {
     let found_pid = child.get_pid();
     let child_inner = child.inner_borrow_ptr();
     let xcode = child_inner.xcode;
-
-    translated_copyout(task_inner.get_user_token(), xcode_ptr, xcode);
+    let token = task_inner.get_user_token();
 
     child.unlock();
     drop(child);
 
     task.unlock();
-    found_pid as isize
+    drop(task);
+    // user page may be swapped in here, release all first
+    match translated_copyout(token, xcode_ptr, xcode) {
+      Some(()) => found_pid as isize,
+      None => -EFAULT,
+    }
   } else {
     task.unlock();
     -2
@@ -87,10 +138,44 @@ pub fn sys_exit(xcode: i32) -> ! {
   exit(xcode)
 }
 
+/// Kill descendant `pid` of current task together with its descendants.
+pub fn sys_kill(pid: usize) -> isize {
+  if kill(pid) {
+    0
+  } else {
+    -ESRCH
+  }
+}
+
 pub fn sys_get_taskinfo() -> isize {
   get_current_pid()
 }
 
+/// Trace syscalls of current task and tasks it forks or execs, `mode` 0
+/// turns tracing off, 1 traces to console and 2 to the log ring.
+pub fn sys_set_trace(mode: usize) -> isize {
+  let trace = match mode {
+    0 => TraceMode::Off,
+    1 => TraceMode::Console,
+    2 => TraceMode::LogRing,
+    _ => return -EINVAL,
+  };
+  get_current_task().inner_borrow_ptr_mut().trace = trace;
+  0
+}
+
+/// Control the sampling profiler, `cmd` 0 stops it, 1 starts it over and
+/// 2 prints the histogram to console.
+pub fn sys_profile(cmd: usize) -> isize {
+  match cmd {
+    0 => profiler::stop(),
+    1 => profiler::start(),
+    2 => profiler::dump(),
+    _ => return -EINVAL,
+  }
+  0
+}
+
 pub fn sys_yield() -> isize {
   yield_();
   0
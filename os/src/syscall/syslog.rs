@@ -0,0 +1,52 @@
+use crate::logging::{clear_log_ring, level_from_usize, log_ring_size, read_log_ring, set_level};
+use crate::mm::translated_byte_buffer;
+use crate::task::{get_current_task, get_current_token};
+use super::{EFAULT, EINVAL, EPERM};
+
+// actions numbered as in Linux
+const SYSLOG_ACTION_READ_ALL: usize = 3;
+const SYSLOG_ACTION_READ_CLEAR: usize = 4;
+const SYSLOG_ACTION_CLEAR: usize = 5;
+const SYSLOG_ACTION_CONSOLE_LEVEL: usize = 8;
+const SYSLOG_ACTION_SIZE_BUFFER: usize = 10;
+
+/// Reading the log ring is allowed to every task, changing it or the
+/// console level only to privileged ones. For `SYSLOG_ACTION_CONSOLE_LEVEL`
+/// `len` is the new level from 0 for off up to 5 for trace.
+pub fn sys_syslog(action: usize, buf: *mut u8, len: usize) -> isize {
+  let privileged = get_current_task().inner_borrow_ptr().privileged;
+  match action {
+    SYSLOG_ACTION_READ_ALL => copy_log_ring(buf, len, false),
+    SYSLOG_ACTION_SIZE_BUFFER => log_ring_size() as isize,
+    _ if !privileged => -EPERM,
+    SYSLOG_ACTION_READ_CLEAR => copy_log_ring(buf, len, true),
+    SYSLOG_ACTION_CLEAR => {
+      clear_log_ring();
+      0
+    }
+    SYSLOG_ACTION_CONSOLE_LEVEL => match level_from_usize(len) {
+      Some(level) => {
+        set_level(level);
+        0
+      }
+      None => -EINVAL,
+    },
+    _ => -EINVAL,
+  }
+}
+
+/// Copy the newest lines fitting in `len` bytes to `buf`, return number of
+/// bytes copied.
+fn copy_log_ring(buf: *mut u8, len: usize, clear: bool) -> isize {
+  let lines = read_log_ring(len, clear);
+  let mut buffers = match translated_byte_buffer(get_current_token(), buf, lines.len()) {
+    Some(buffers) => buffers,
+    None => return -EFAULT,
+  };
+  let mut copied = 0;
+  for buffer in buffers.iter_mut() {
+    buffer.copy_from_slice(&lines[copied..copied + buffer.len()]);
+    copied += buffer.len();
+  }
+  copied as isize
+}
@@ -0,0 +1,92 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use crate::config::CLOCK_FREQ;
+use crate::logging::log_line;
+use crate::mm::{translated_byte_buffer, translated_str};
+use crate::task::{get_current_token, TraceMode};
+use crate::timer::get_time;
+use super::*;
+
+// bytes of a write shown in trace
+const MAX_PREVIEW_LEN: usize = 32;
+
+/// Dispatch a syscall and trace it, the call is decoded before dispatch
+/// since exec replaces the address space.
+pub fn traced_syscall(which: usize, args: [usize; 3], mode: TraceMode) -> isize {
+  let call = decode(which, args);
+  if which == SYSCALL_EXIT {
+    log_line("strace", format_args!("{} = ?", call), mode == TraceMode::Console);
+  }
+  let start = get_time();
+  let ret = dispatch(which, args);
+  let micros = (get_time() - start) * 1_000_000 / CLOCK_FREQ;
+  log_line(
+    "strace",
+    format_args!("{} = {} <{}us>", call, ret, micros),
+    mode == TraceMode::Console,
+  );
+  ret
+}
+
+fn decode(which: usize, args: [usize; 3]) -> String {
+  let token = get_current_token();
+  match which {
+    SYSCALL_READ => format!("read({}, {:#x}, {})", args[0], args[1], args[2]),
+    SYSCALL_WRITE => format!(
+      "write({}, {}, {})",
+      args[0],
+      preview(token, args[1] as *const u8, args[2]),
+      args[2],
+    ),
+    SYSCALL_EXIT => format!("exit({})", args[0] as i32),
+    SYSCALL_SYSLOG => format!("syslog({}, {:#x}, {})", args[0], args[1], args[2]),
+    SYSCALL_YIELD => "yield()".to_string(),
+    SYSCALL_KILL => format!("kill({})", args[0]),
+    SYSCALL_GET_TIME => "get_time()".to_string(),
+    SYSCALL_GETPID => "getpid()".to_string(),
+    SYSCALL_FORK => "fork()".to_string(),
+    SYSCALL_EXEC => format!(
+      "exec({}, {:#x})",
+      preview_str(token, args[0] as *const u8),
+      args[1],
+    ),
+    SYSCALL_SBRK => format!("sbrk({})", args[0] as i32),
+    SYSCALL_WAITPID => format!("waitpid({}, {:#x})", args[0] as isize, args[1]),
+    SYSCALL_GET_TASKINFO => "get_taskinfo()".to_string(),
+    SYSCALL_SET_TRACE => format!("set_trace({})", args[0]),
+    SYSCALL_PROFILE => format!("profile({})", args[0]),
+    SYSCALL_CAPTURE => format!("capture({}, {:#x}, {})", args[0], args[1], args[2]),
+    _ => format!("syscall_{}({:#x}, {:#x}, {:#x})", which, args[0], args[1], args[2]),
+  }
+}
+
+/// Leading bytes of user string as a quoted string.
+fn preview_str(token: usize, ptr: *const u8) -> String {
+  match translated_str(token, ptr, MAX_PREVIEW_LEN) {
+    Some(text) => {
+      let shown: String = text.chars().take(MAX_PREVIEW_LEN).collect();
+      if shown.len() < text.len() {
+        format!("{:?}...", shown)
+      } else {
+        format!("{:?}", shown)
+      }
+    }
+    None => format!("{:#x}", ptr as usize),
+  }
+}
+
+/// Leading bytes of user buffer as a quoted string.
+fn preview(token: usize, buf: *const u8, len: usize) -> String {
+  let shown = len.min(MAX_PREVIEW_LEN);
+  let bytes: Vec<u8> = match translated_byte_buffer(token, buf, shown) {
+    Some(buffers) => buffers.iter().flat_map(|buffer| buffer.iter().copied()).collect(),
+    None => return format!("{:#x}", buf as usize),
+  };
+  let text = String::from_utf8_lossy(&bytes);
+  if shown < len {
+    format!("{:?}...", text)
+  } else {
+    format!("{:?}", text)
+  }
+}
@@ -0,0 +1,166 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use crate::config::*;
+use crate::mm::{KERNEL_SPACE, MapPermission, VirtAddr};
+use crate::sync::SpinMutex;
+
+// free stacks kept mapped, so that fork after exit does not map again
+const MAPPED_STACK_CACHE: usize = 16;
+// top of stack slot 0, below the guard page under trampoline
+const STACKS_TOP: usize = TRAMPOLINE - PAGE_SIZE;
+// slots ever used, read without lock by kernel trap handler
+static SLOTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Slots of kernel stacks, a slot is reused by any task once freed.
+struct KernelStackPool {
+  current: usize,
+  // free slots whose stacks are still mapped
+  mapped: Vec<usize>,
+  // free slots whose stacks have been unmapped
+  unmapped: Vec<usize>,
+}
+
+impl KernelStackPool {
+  pub fn new() -> Self {
+    Self {
+      current: 0,
+      mapped: Vec::new(),
+      unmapped: Vec::new(),
+    }
+  }
+
+  /// Return `None` if there is no frame left for the stack.
+  pub fn alloc(&mut self) -> Option<usize> {
+    if let Some(slot) = self.mapped.pop() {
+      return Some(slot);
+    }
+    let slot = self.unmapped.pop().unwrap_or_else(|| {
+      self.current += 1;
+      SLOTS.store(self.current, Ordering::Release);
+      self.current - 1
+    });
+    let (bottom, top) = kernel_stack_position(slot);
+    let mapped = unsafe {
+      KERNEL_SPACE.lock()
+        .insert_framed_area(bottom.into(), top.into(), MapPermission::R | MapPermission::W)
+    };
+    if mapped.is_none() {
+      self.unmapped.push(slot);
+    }
+    mapped.map(|_| slot)
+  }
+
+  pub fn dealloc(&mut self, slot: usize) {
+    assert!(slot < self.current);
+    if self.mapped.len() < MAPPED_STACK_CACHE {
+      self.mapped.push(slot);
+    } else {
+      unmap_stack(slot);
+      self.unmapped.push(slot);
+    }
+  }
+
+  /// Unmap every cached stack, return number of pages freed.
+  pub fn shrink(&mut self) -> usize {
+    let freed = self.mapped.len() * KERNEL_STACK_SIZE / PAGE_SIZE;
+    while let Some(slot) = self.mapped.pop() {
+      unmap_stack(slot);
+      self.unmapped.push(slot);
+    }
+    freed
+  }
+}
+
+fn unmap_stack(slot: usize) {
+  let (bottom, _) = kernel_stack_position(slot);
+  KERNEL_SPACE.lock()
+    .remove_area_with_start_vpn(VirtAddr::from(bottom).into());
+}
+
+lazy_static! {
+  static ref KERNEL_STACK_POOL: SpinMutex<KernelStackPool> =
+    SpinMutex::new(KernelStackPool::new());
+}
+
+/// Unmap kernel stacks cached by the pool, return number of pages freed.
+pub fn shrink_kernel_stacks() -> usize {
+  KERNEL_STACK_POOL.lock().shrink()
+}
+
+pub struct KernelStack {
+  slot: usize,
+}
+
+impl KernelStack {
+  /// Return `None` if there is no frame left for the stack.
+  pub fn new() -> Option<Self> {
+    let slot = KERNEL_STACK_POOL.lock().alloc()?;
+    Some(Self { slot })
+  }
+
+  #[allow(unused)]
+  pub fn push_on_top<T>(&self, value: T) -> *mut T
+    where T: Sized
+  {
+    let kernel_stack_top = self.get_top();
+    let ptr_mut = (kernel_stack_top - core::mem::size_of::<T>()) as *mut T;
+    unsafe { *ptr_mut = value; }
+    ptr_mut
+  }
+
+  pub fn get_top(&self) -> usize {
+    kernel_stack_position(self.slot).1
+  }
+}
+
+impl Drop for KernelStack {
+  fn drop(&mut self) {
+    KERNEL_STACK_POOL.lock().dealloc(self.slot);
+  }
+}
+
+/// # Layout
+/// ```
+/// +-------------------+
+/// |    Trampoline     |
+/// |-------------------|
+/// |    Guard Page     |
+/// |-------------------|
+/// |  Kernel Stack 0   |
+/// |-------------------|
+/// |    Guard Page     |
+/// |-------------------|
+/// |  Kernel Stack 1   |
+/// |-------------------|
+/// |        ...        |
+/// |                   |
+/// ```
+pub fn kernel_stack_position(slot: usize) -> (usize, usize) {
+  let top = STACKS_TOP - slot * (KERNEL_STACK_SIZE + PAGE_SIZE);
+  let bottom = top - KERNEL_STACK_SIZE;
+  (bottom, top)
+}
+
+/// Slot whose stack or guard page below holds `addr`, and whether it is
+/// the guard page.
+fn locate_slot(addr: usize) -> Option<(usize, bool)> {
+  let offset = STACKS_TOP.checked_sub(addr)?.checked_sub(1)?;
+  let stride = KERNEL_STACK_SIZE + PAGE_SIZE;
+  let slot = offset / stride;
+  (slot < SLOTS.load(Ordering::Acquire)).then_some((slot, offset % stride >= KERNEL_STACK_SIZE))
+}
+
+/// Slot of the kernel stack whose guard page holds `addr`.
+pub fn guard_page_slot(addr: usize) -> Option<usize> {
+  locate_slot(addr)
+    .filter(|&(_, in_guard)| in_guard)
+    .map(|(slot, _)| slot)
+}
+
+/// `[bottom, top)` of the kernel stack holding `addr`.
+pub fn kernel_stack_bounds(addr: usize) -> Option<(usize, usize)> {
+  locate_slot(addr)
+    .filter(|&(_, in_guard)| !in_guard)
+    .map(|(slot, _)| kernel_stack_position(slot))
+}
@@ -2,6 +2,7 @@ use alloc::collections::VecDeque;
 use alloc::sync::Arc;
 use lazy_static::lazy_static;
 use crate::sync::SpinMutex;
+use crate::task::processor::wake_idle_hart;
 use crate::task::task::TaskControlBlock;
 
 lazy_static! {
@@ -25,10 +26,19 @@ impl TaskManager {
   fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
     self.ready_queue.pop_front()
   }
+
+  fn is_empty(&self) -> bool {
+    self.ready_queue.is_empty()
+  }
 }
 
 pub fn add_task(task: Arc<TaskControlBlock>) {
   TASK_MANAGER.lock().add(task);
+  wake_idle_hart();
+}
+
+pub fn has_ready_task() -> bool {
+  !TASK_MANAGER.lock().is_empty()
 }
 
 pub fn fetch_task() -> Option<Arc<TaskControlBlock>> {
@@ -2,22 +2,31 @@ mod switch;
 mod context;
 mod task;
 mod pid;
+mod kernel_stack;
 mod manager;
 mod processor;
+mod oom;
 
 use alloc::sync::Arc;
 use lazy_static::lazy_static;
 use log::info;
 
-use task::{TaskControlBlock, TaskStatus};
+use task::TaskStatus;
+pub(crate) use task::TaskControlBlock;
+pub(crate) use task::TraceMode;
 use processor::{schedule, take_current_task};
 pub(crate) use manager::add_task;
-pub(crate) use processor::{current_task, scheduler, current_cpu};
+pub(crate) use processor::{current_task, scheduler, current_cpu, lock_state_of};
+pub(crate) use oom::with_oom_retry;
+pub(crate) use kernel_stack::{guard_page_slot, kernel_stack_bounds};
 
+use oom::for_each_descendant;
+use crate::dtb::bootargs;
 use crate::loader::{get_app_data_by_name, list_apps};
 #[cfg(feature = "sbrk_lazy_alloc")]
 use crate::mm::VirtAddr;
-use crate::sbi::shutdown;
+use crate::mm::VirtPageNum;
+use crate::smp::shutdown;
 use crate::trap::context::TrapContext;
 
 pub fn init() {
@@ -27,9 +36,20 @@ pub fn init() {
 }
 
 lazy_static! {
-  pub static ref INITPROC: Arc<TaskControlBlock> = Arc::new(
-    TaskControlBlock::new_for_initproc(get_app_data_by_name("initproc").unwrap())
-  );
+  pub static ref INITPROC: Arc<TaskControlBlock> = {
+    let name = initproc_name();
+    let elf_data = get_app_data_by_name(name)
+      .unwrap_or_else(|| panic!("init app {:?} not found", name));
+    Arc::new(TaskControlBlock::new_for_initproc(elf_data))
+  };
+}
+
+/// App run as initproc, `init=` in boot arguments or `initproc`.
+fn initproc_name() -> &'static str {
+  bootargs()
+    .split_whitespace()
+    .find_map(|arg| arg.strip_prefix("init="))
+    .unwrap_or("initproc")
 }
 
 pub fn add_initproc() {
@@ -56,13 +76,88 @@ pub fn yield_() {
   mu.unlock();
 }
 
+/// Panic if current hart holds a spin lock, current task may sleep here.
+pub fn might_sleep() {
+  let noff = current_cpu().noff;
+  if noff != 0 {
+    panic!("sleeping with {} spin locks held", noff);
+  }
+}
+
+/// Sleep until [`wake_up`]. Current task must be locked by caller and put
+/// where its waker finds it, the waker then waits for the lock, which is
+/// released once current task is switched out. A killed task only yields,
+/// so it gets to exit, caller must check its condition again.
+pub fn block_current(task: Arc<TaskControlBlock>) {
+  // the task lock is the only one allowed
+  let noff = current_cpu().noff;
+  if noff != 1 {
+    panic!("sleeping with {} spin locks held", noff - 1);
+  }
+  // killer sets the flag before taking the task lock, then wakes it
+  if !task.is_killed() {
+    task.inner_borrow_ptr_mut().task_status = TaskStatus::Blocked;
+  }
+  let mu = task.get_mutex();
+  drop(task);
+  schedule();
+  mu.unlock();
+}
+
+/// Make a task blocked by [`block_current`] runnable again, return `false`
+/// if it is not blocked, e.g. woken already when it was killed.
+pub fn wake_up(task: Arc<TaskControlBlock>) -> bool {
+  task.lock();
+  let inner = task.inner_borrow_ptr_mut();
+  if inner.task_status != TaskStatus::Blocked {
+    task.unlock();
+    return false;
+  }
+  inner.task_status = TaskStatus::Ready;
+  task.unlock();
+  add_task(task);
+  true
+}
+
+/// Make `task` exit before it returns to user mode, it is woken if it
+/// sleeps.
+fn kill_task(task: &Arc<TaskControlBlock>) {
+  task.kill();
+  wake_up(Arc::clone(task));
+}
+
+/// Keeps pages of current task from being swapped out while alive.
+pub struct PinnedPages(Option<Arc<TaskControlBlock>>);
+
+impl PinnedPages {
+  pub fn current() -> Self {
+    let task = current_task();
+    if let Some(task) = task.as_ref() {
+      task.inner_borrow_ptr_mut().pinned += 1;
+    }
+    Self(task)
+  }
+}
+
+impl Drop for PinnedPages {
+  fn drop(&mut self) {
+    if let Some(task) = self.0.as_ref() {
+      task.inner_borrow_ptr_mut().pinned -= 1;
+    }
+  }
+}
+
 pub const INITPROC_PID: usize = 0;
+/// Exit code of tasks killed by kernel.
+pub const KILLED_XCODE: i32 = -9;
+/// Exit code of tasks faulting in user stack guard region.
+pub const STACK_OVERFLOW_XCODE: i32 = -11;
 
 pub fn exit(xcode: i32) -> ! {
   let task = take_current_task().unwrap();
   let pid = task.get_pid();
   if pid == INITPROC_PID {
-    shutdown();
+    shutdown(xcode != 0);
   }
   let mut task_inner = task.inner_borrow_ptr_mut();
 
@@ -96,6 +191,29 @@ pub fn exit(xcode: i32) -> ! {
   panic!("Unreachable in exit()")
 }
 
+/// Kill descendant `pid` of current task and its descendants, they exit
+/// before returning to user mode. Return `false` if there is no such task.
+pub fn kill(pid: usize) -> bool {
+  let mut found = false;
+  for_each_descendant(&get_current_task(), &mut |task| {
+    if task.get_pid() != pid {
+      return;
+    }
+    found = true;
+    kill_task(task);
+    for_each_descendant(task, &mut kill_task);
+  });
+  found
+}
+
+/// Exit current task if it has been killed by others.
+pub fn exit_if_killed() {
+  let killed = get_current_task().is_killed();
+  if killed {
+    exit(KILLED_XCODE);
+  }
+}
+
 pub fn get_current_pid() -> isize {
   get_current_task().get_pid() as isize
 }
@@ -104,6 +222,11 @@ pub fn get_current_token() -> usize {
   get_current_task().inner_borrow_ptr().get_user_token()
 }
 
+/// Token with ASID to return to user space of current task.
+pub fn get_current_switch_token() -> usize {
+  get_current_task().inner_borrow_ptr().memory_set.switch_token()
+}
+
 pub fn get_current_trap_cx() -> &'static mut TrapContext {
   get_current_task().inner_borrow_ptr().get_trap_cx()
 }
@@ -123,7 +246,27 @@ pub fn change_program_brk(size: i32) -> Option<usize> {
 #[cfg(feature = "sbrk_lazy_alloc")]
 pub fn lazy_alloc_page(addr: VirtAddr) -> bool {
   if let Some(task) = current_task() {
-    task.lazy_alloc_page(addr)
+    with_oom_retry(|| task.lazy_alloc_page(addr).then_some(())).is_some()
+  } else {
+    false
+  }
+}
+
+/// Grow user stack of current task down to `vpn`, return `false` if
+/// physical frames run out.
+pub fn grow_user_stack(vpn: VirtPageNum) -> bool {
+  if let Some(task) = current_task() {
+    with_oom_retry(|| task.inner_borrow_ptr_mut().memory_set.grow_stack(vpn)).is_some()
+  } else {
+    false
+  }
+}
+
+/// Bring `vpn` of current task in as a page fault from user would, return
+/// `false` if physical frames run out.
+pub fn fault_in_page(vpn: VirtPageNum) -> bool {
+  if let Some(task) = current_task() {
+    with_oom_retry(|| task.inner_borrow_ptr_mut().fault_in(vpn)).is_some()
   } else {
     false
   }
@@ -0,0 +1,133 @@
+use alloc::sync::Arc;
+use log::{debug, warn};
+use crate::mm::{heap_stats, shrink_heap};
+use crate::task::{get_current_tcb_ref, kill_task, yield_, INITPROC};
+use crate::task::kernel_stack::shrink_kernel_stacks;
+use crate::task::task::{TaskControlBlock, TaskStatus};
+
+// pages swapped out before retrying a failed allocation
+const RECLAIM_BATCH: usize = 32;
+// tasks killed or waited for before a failed allocation gives up
+const MAX_OOM_ROUNDS: usize = 16;
+
+/// Visit every descendant of `task`, the parent is locked while visiting.
+pub(super) fn for_each_descendant<F>(task: &Arc<TaskControlBlock>, f: &mut F)
+  where F: FnMut(&Arc<TaskControlBlock>)
+{
+  task.lock();
+  for child in task.inner_borrow_ptr().children.iter() {
+    f(child);
+    for_each_descendant(child, f);
+  }
+  task.unlock();
+}
+
+/// Swap out at most `budget` pages from tasks not running on other harts
+/// and not pinned. Return number of pages swapped out.
+pub fn reclaim_pages(budget: usize) -> usize {
+  let current_pid = get_current_tcb_ref().get_pid();
+  let mut reclaimed = 0;
+  for_each_descendant(&INITPROC, &mut |task| {
+    if reclaimed == budget {
+      return;
+    }
+    let is_current = task.get_pid() == current_pid;
+    // keep parent's lock order, and stop scheduler picking it meanwhile
+    if !is_current {
+      task.lock();
+    }
+    let inner = task.inner_borrow_ptr_mut();
+    if inner.pinned == 0 && (is_current || inner.task_status == TaskStatus::Ready) {
+      reclaimed += inner.memory_set.swap_out(budget - reclaimed);
+    }
+    if !is_current {
+      task.unlock();
+    }
+  });
+  if reclaimed > 0 {
+    debug!("[kernel] reclaimed {} pages for pid {}", reclaimed, current_pid);
+  }
+  reclaimed
+}
+
+/// Kill the live task holding most resident frames, initproc excluded.
+/// Victims exit before returning to user mode.
+///
+/// Return `false` if there is nothing to kill or current task is the
+/// victim, otherwise caller could retry after the victim releases its frames.
+pub fn oom_kill() -> bool {
+  let current = get_current_tcb_ref();
+  if current.is_killed() {
+    return false;
+  }
+  // a former victim has not exited yet, give it a chance to run
+  let mut pending = false;
+  let mut victim: Option<(usize, usize)> = None;
+  for_each_descendant(&INITPROC, &mut |task| {
+    // candidates may run on other harts, what they change is atomic
+    task.lock();
+    let zombie = task.inner_borrow_ptr().is_zombie();
+    task.unlock();
+    if zombie {
+      return;
+    }
+    if task.is_killed() {
+      pending = true;
+      return;
+    }
+    let resident = task.inner_borrow_ptr().memory_set.resident_pages();
+    if victim.map_or(true, |(_, max)| resident > max) {
+      victim = Some((task.get_pid(), resident));
+    }
+  });
+  if pending {
+    yield_();
+    return true;
+  }
+  let (victim_pid, resident) = match victim {
+    Some(v) => v,
+    None => {
+      warn!("[kernel] out of memory, no task could be killed");
+      return false;
+    }
+  };
+
+  let heap = heap_stats();
+  warn!(
+    "[kernel] out of memory in pid {}, killed pid {} holding {} frames, heap {}/{} KiB",
+    current.get_pid(), victim_pid, resident, heap.actual >> 10, heap.total >> 10,
+  );
+  if victim_pid == current.get_pid() {
+    current.kill();
+    return false;
+  }
+  for_each_descendant(&INITPROC, &mut |task| {
+    if task.get_pid() == victim_pid {
+      kill_task(task);
+    }
+  });
+  yield_();
+  true
+}
+
+/// Run `f` until it succeeds, empty heap chunks and cached kernel stacks
+/// are freed and pages are reclaimed on each failure, and [`oom_kill`] is
+/// invoked when nothing could be reclaimed. Give up after
+/// `MAX_OOM_ROUNDS` of [`oom_kill`], victims may never get to exit.
+pub fn with_oom_retry<T, F>(mut f: F) -> Option<T>
+  where F: FnMut() -> Option<T>
+{
+  let mut rounds = 0;
+  loop {
+    if let Some(ret) = f() {
+      return Some(ret);
+    }
+    if shrink_heap() > 0 || shrink_kernel_stacks() > 0 || reclaim_pages(RECLAIM_BATCH) > 0 {
+      continue;
+    }
+    rounds += 1;
+    if rounds > MAX_OOM_ROUNDS || !oom_kill() {
+      return None;
+    }
+  }
+}
@@ -3,10 +3,14 @@ use alloc::sync::Arc;
 use core::arch::asm;
 use core::cell::UnsafeCell;
 use core::ops::Index;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use lazy_static::lazy_static;
-use crate::common::{cpuid, intr_get, intr_on, pop_off, push_off};
+use riscv::register::sip;
+use crate::common::{cpuid, intr_get, intr_off, intr_on, pop_off, push_off};
 use crate::config::MAX_CPU_NUM;
-use crate::task::{add_task, context::TaskContext, manager::fetch_task, switch::__switch, task::{TaskControlBlock, TaskStatus}};
+use crate::sbi::send_ipi;
+use crate::smp::{handle_stop_request, suspend};
+use crate::task::{add_task, context::TaskContext, manager::{fetch_task, has_ready_task}, switch::__switch, task::{TaskControlBlock, TaskStatus}};
 use crate::trap::context::TrapContext;
 
 pub struct Processors {
@@ -23,6 +27,9 @@ impl Index<usize> for Processors {
   }
 }
 
+// harts sleeping in idle loop
+static IDLE_HARTS: AtomicUsize = AtomicUsize::new(0);
+
 lazy_static! {
   pub static ref PROCESSOR: Processors = Processors {
     processors: {
@@ -65,6 +72,12 @@ pub fn current_cpu() -> &'static mut Processor {
   unsafe { &mut *PROCESSOR[cpuid()].get() }
 }
 
+/// `noff` and `intena` of `hartid`, read without synchronization.
+pub fn lock_state_of(hartid: usize) -> (isize, bool) {
+  let processor = unsafe { &*PROCESSOR[hartid].get() };
+  (processor.noff, processor.intena)
+}
+
 pub fn take_current_task() -> Option<Arc<TaskControlBlock>> {
   current_cpu().take_current()
 }
@@ -105,9 +118,12 @@ pub fn schedule() {
   let switched_task_cx_ptr = match take_current_task() {
     Some(task) => {
       let mut inner = task.inner_borrow_ptr_mut();
-      inner.task_status = TaskStatus::Ready;
       let task_cx = &mut inner.task_cx as *mut TaskContext;
-      add_task(task);
+      // a blocked task is kept by its wait queue
+      if inner.task_status != TaskStatus::Blocked {
+        inner.task_status = TaskStatus::Ready;
+        add_task(task);
+      }
       task_cx
     }
     None => {
@@ -128,10 +144,10 @@ pub fn schedule() {
 pub fn scheduler() {
   let processor = current_cpu();
   loop {
+    handle_stop_request();
     intr_on();
     if let Some(next_task) = fetch_task() {
       next_task.lock();
-      let pid = next_task.pid.0;
       let this_scheduler_cx = processor.get_scheduler_cx_mut_ptr();
       let mut next_task_inner = next_task.inner_borrow_ptr_mut();
       if next_task_inner.task_status != TaskStatus::Ready {
@@ -152,12 +168,38 @@ pub fn scheduler() {
       processor.current = None;
 
       mu.unlock();
-      if pid < 2 {
-        intr_on();
-        unsafe {
-          asm!("wfi");
-        }
-      }
+    } else {
+      idle_wait();
+    }
+  }
+}
+
+/// Sleep until another hart adds a task and wakes current hart by IPI.
+fn idle_wait() {
+  let mask = 1 << cpuid();
+  unsafe {
+    sip::clear_ssoft();
+  }
+  IDLE_HARTS.fetch_or(mask, Ordering::SeqCst);
+  // task added before the bit is set sends no IPI
+  if !has_ready_task() {
+    // IPI wakes wfi without a trap
+    intr_off();
+    suspend();
+  }
+  IDLE_HARTS.fetch_and(!mask, Ordering::SeqCst);
+}
+
+/// Wake one idle hart to run newly added task.
+pub fn wake_idle_hart() {
+  let claimed = IDLE_HARTS.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |idle| {
+    if idle == 0 {
+      None
+    } else {
+      Some(idle & (idle - 1))
     }
+  });
+  if let Ok(idle) = claimed {
+    send_ipi(1 << idle.trailing_zeros());
   }
 }
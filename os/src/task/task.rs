@@ -1,14 +1,22 @@
+use alloc::string::String;
 use alloc::sync::{Weak, Arc};
 use alloc::vec::Vec;
 use core::cell::{Ref, RefMut};
-use core::ptr;
+use core::mem::size_of;
+use core::{iter, ptr};
+use core::sync::atomic::{AtomicBool, Ordering};
 use cfg_if::cfg_if;
 use crate::config::*;
-use crate::mm::{KERNEL_SPACE, MapPermission, MemorySet, PhysPageNum, VirtAddr};
+use crate::mm::{
+  translated_byte_buffer, translated_copyout, KERNEL_SPACE, MapPermission, MemorySet, PhysPageAccess,
+  PhysPageNum, VirtAddr, VirtPageNum,
+};
 use crate::sync::{SpinLock, UPSafeCell};
 use crate::task::{
   context::TaskContext,
-  pid::{kernel_stack_position, KernelStack, pid_alloc, PidHandle},
+  kernel_stack::KernelStack,
+  pid::{pid_alloc, PidHandle},
+  INITPROC_PID,
 };
 use crate::trap::{context::TrapContext, trap_handler};
 
@@ -16,37 +24,54 @@ use crate::trap::{context::TrapContext, trap_handler};
 pub enum TaskStatus {
   Ready,
   Running,
+  // Sleeping in a wait queue, which adds it back when woken
+  Blocked,
   Zombie,
   #[allow(unused)]
   Exited,
 }
 
+/// Where syscalls of a task are traced.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum TraceMode {
+  Off,
+  Console,
+  LogRing,
+}
+
 pub struct TaskControlBlock {
   // lock
   mutex: SpinLock,
   // immutable
   pub pid: PidHandle,
   pub kernel_stack: KernelStack,
+  // set by other tasks, task exits before returning to user mode
+  killed: AtomicBool,
   // mutable
   inner: UPSafeCell<TaskControlBlockInner>,
 }
 
 impl TaskControlBlock {
   /// Only used for creating initproc
-  pub fn new_for_initproc(elf_data: &[u8]) -> Self {
+  pub fn new_for_initproc(elf_data: &'static [u8]) -> Self {
     let pid = pid_alloc();
-    let kernel_stack = KernelStack::new(&pid);
-    let inner = unsafe { UPSafeCell::new(TaskControlBlockInner::new(elf_data, pid.0)) };
+    let kernel_stack = KernelStack::new().unwrap();
+    let inner = unsafe {
+      UPSafeCell::new(TaskControlBlockInner::new(elf_data, kernel_stack.get_top()))
+    };
     Self {
       mutex: SpinLock::new(),
       pid,
       kernel_stack,
+      killed: AtomicBool::new(false),
       inner,
     }
   }
 
-  pub fn exec(&self, elf_data: &[u8]) {
-    let (memory_set, user_stack_top, _, entry_point) = MemorySet::from_elf(elf_data);
+  /// Current address space is kept if physical frames run out. `args` are
+  /// passed to user space as `argc` in a0 and `argv` in a1.
+  pub fn exec(&self, elf_data: &'static [u8], args: &[String]) -> Option<()> {
+    let (memory_set, user_stack_top, _, entry_point) = MemorySet::from_elf(elf_data)?;
 
     let trap_cx_ppn = memory_set.translate(VirtAddr::from(TRAP_CONTEXT).into()).unwrap().ppn();
 
@@ -54,22 +79,46 @@ impl TaskControlBlock {
     inner.trap_cx_ppn = trap_cx_ppn;
     inner.memory_set = memory_set;
 
+    // argv pointers then strings at the top of user stack, which is mapped
+    let token = inner.get_user_token();
+    let argv_base = user_stack_top - (args.len() + 1) * size_of::<usize>();
+    let mut user_sp = argv_base;
+    for (i, arg) in args.iter().enumerate() {
+      user_sp -= arg.len() + 1;
+      let bytes = arg.bytes().chain(iter::once(0));
+      // arguments fit in initial user stack, checked by sys_exec
+      translated_byte_buffer(token, user_sp as *const u8, arg.len() + 1)
+        .unwrap()
+        .iter_mut()
+        .flat_map(|buffer| buffer.iter_mut())
+        .zip(bytes)
+        .for_each(|(dst, byte)| *dst = byte);
+      translated_copyout(token, (argv_base as *mut usize).wrapping_add(i), user_sp).unwrap();
+    }
+    translated_copyout(token, (argv_base as *mut usize).wrapping_add(args.len()), 0).unwrap();
+    // stack pointer is 16 bytes aligned
+    user_sp &= !0xf;
+
     let trap_cx = inner.get_trap_cx();
     *trap_cx = TrapContext::app_init_context(
       entry_point,
-      user_stack_top,
+      user_sp,
       KERNEL_SPACE.lock().token(),
       self.kernel_stack.get_top(),
       trap_handler as usize,
     );
+    trap_cx.regs[10] = args.len();
+    trap_cx.regs[11] = argv_base;
+    Some(())
   }
 
-  pub fn fork(self: &Arc<TaskControlBlock>) -> Arc<TaskControlBlock> {
+  /// Return `None` if physical frames run out.
+  pub fn fork(self: &Arc<TaskControlBlock>) -> Option<Arc<TaskControlBlock>> {
     let pid = pid_alloc();
-    let kernel_stack = KernelStack::new(&pid);
+    let kernel_stack = KernelStack::new()?;
 
     let parent_inner = self.inner_borrow_ptr_mut();
-    let memory_set = MemorySet::from_another(&parent_inner.memory_set);
+    let memory_set = MemorySet::from_another(&parent_inner.memory_set)?;
     let trap_cx_ppn = memory_set.translate(VirtAddr::from(TRAP_CONTEXT).into()).unwrap().ppn();
     let kernel_stack_top = kernel_stack.get_top();
 
@@ -83,17 +132,23 @@ impl TaskControlBlock {
       parent: Some(Arc::downgrade(self)),
       children: Vec::new(),
       xcode: 0,
+      // only direct children of initproc, see `privileged`
+      privileged: self.get_pid() == INITPROC_PID,
+      trace: parent_inner.trace,
+      capture: parent_inner.capture,
+      pinned: 0,
     };
     let new_tcb = TaskControlBlock {
       mutex: SpinLock::new(),
       pid,
       kernel_stack,
+      killed: AtomicBool::new(false),
       inner: unsafe { UPSafeCell::new(tcb_inner) },
     };
     new_tcb.inner_borrow_ptr_mut().get_trap_cx().kernel_sp = kernel_stack_top;
     let ret = Arc::new(new_tcb);
     parent_inner.children.push(Arc::clone(&ret));
-    ret
+    Some(ret)
   }
 
   #[allow(unused)]
@@ -118,6 +173,15 @@ impl TaskControlBlock {
     return self.pid.0;
   }
 
+  /// Make task exit before it returns to user mode.
+  pub fn kill(&self) {
+    self.killed.store(true, Ordering::Release);
+  }
+
+  pub fn is_killed(&self) -> bool {
+    self.killed.load(Ordering::Acquire)
+  }
+
   pub fn change_brk(&self, size: i32) -> Option<usize> {
     self.inner_borrow_ptr_mut().change_brk(size)
   }
@@ -156,16 +220,26 @@ pub struct TaskControlBlockInner {
   pub parent: Option<Weak<TaskControlBlock>>,
   pub children: Vec<Arc<TaskControlBlock>>,
   pub xcode: i32,
+  // Set for initproc and tasks it forks directly such as the shell, not
+  // inherited, as every task descends from initproc. Allowed to configure
+  // kernel
+  pub privileged: bool,
+  // Kept across fork and exec
+  pub trace: TraceMode,
+  // Stdout is also captured for sys_capture, kept across fork and exec
+  pub capture: bool,
+  // Kernel is accessing user pages through physical addresses, which
+  // must not be swapped out meanwhile
+  pub pinned: usize,
 }
 
 impl TaskControlBlockInner {
-  pub fn new(elf_data: &[u8], pid: usize) -> Self {
-    let (memory_set, user_stack_top, heap_bottom, entry_point) = MemorySet::from_elf(elf_data);
+  pub fn new(elf_data: &'static [u8], kernel_top: usize) -> Self {
+    let (memory_set, user_stack_top, heap_bottom, entry_point) = MemorySet::from_elf(elf_data).unwrap();
     let trap_cx_ppn = memory_set
       .translate(VirtAddr::from(TRAP_CONTEXT).into())
       .unwrap()
       .ppn();
-    let (_, kernel_top) = kernel_stack_position(pid);
     let tcb = Self {
       task_status: TaskStatus::Ready,
       task_cx: TaskContext::goto_forkret(kernel_top),
@@ -176,6 +250,10 @@ impl TaskControlBlockInner {
       parent: None,
       children: Vec::new(),
       xcode: 0,
+      privileged: true,
+      trace: TraceMode::Off,
+      capture: false,
+      pinned: 0,
     };
     let trap_cx = tcb.get_trap_cx();
     let to_write_cx = TrapContext::app_init_context(
@@ -213,7 +291,11 @@ impl TaskControlBlockInner {
         ok = if size < 0 {
           self.memory_set.shrink_to(VirtAddr::from(self.heap_bottom), VirtAddr::from(new_brk as usize))
         } else {
-          self.memory_set.append_to(VirtAddr::from(self.heap_bottom), VirtAddr::from(new_brk as usize))
+          crate::task::with_oom_retry(|| {
+            self.memory_set
+              .append_to(VirtAddr::from(self.heap_bottom), VirtAddr::from(new_brk as usize))
+              .then_some(())
+          }).is_some()
         }
       }
     }
@@ -225,6 +307,7 @@ impl TaskControlBlockInner {
     }
   }
 
+  /// Return `false` if physical frames run out.
   #[cfg(feature = "sbrk_lazy_alloc")]
   pub fn lazy_alloc_page(&mut self, addr: VirtAddr) -> bool {
     unsafe {
@@ -232,9 +315,24 @@ impl TaskControlBlockInner {
         addr,
         (addr.0 + 1).into(),
         MapPermission::R | MapPermission::W | MapPermission::U,
-      );
+      ).is_some()
+    }
+  }
+
+  /// Make `vpn` resident the way a page fault from user would, nothing is
+  /// done for pages user could not access. Return `None` if physical
+  /// frames run out.
+  pub fn fault_in(&mut self, vpn: VirtPageNum) -> Option<()> {
+    #[cfg(feature = "sbrk_lazy_alloc")] {
+      let mapped = self.memory_set.translate(vpn)
+        .map_or(false, |pte| pte.is_valid() || pte.is_swapped());
+      let start = VirtAddr::from(vpn).0;
+      if !mapped && !self.memory_set.is_unloaded(vpn)
+        && start < self.program_brk && start + PAGE_SIZE > self.heap_bottom {
+        return self.lazy_alloc_page(start.into()).then_some(());
+      }
     }
-    true
+    self.memory_set.fault_in(vpn)
   }
 }
 
@@ -1,5 +1,13 @@
+use core::fmt::{self, Display, Formatter};
 use riscv::register::sstatus::{self, SPP, Sstatus};
 
+const REG_NAMES: [&str; 32] = [
+  "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+  "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+  "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+  "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
 #[repr(C)]
 pub struct TrapContext {
   pub regs: [usize; 32],
@@ -36,3 +44,13 @@ impl TrapContext {
     cx
   }
 }
+
+impl Display for TrapContext {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    for (i, (name, reg)) in REG_NAMES.iter().zip(self.regs.iter()).enumerate() {
+      write!(f, "{:>4}: {:#018x}", name, reg)?;
+      f.write_str(if i % 4 == 3 { "\n" } else { "  " })?;
+    }
+    write!(f, "sstatus: {:#x}, sepc: {:#x}", self.sstatus.bits(), self.sepc)
+  }
+}
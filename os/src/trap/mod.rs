@@ -1,17 +1,23 @@
 use core::arch::{asm, global_asm};
+use core::ptr::addr_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use log::debug;
 use riscv::register::{stvec::TrapMode, scause::{
   Exception,
   Trap,
-}, stval, stvec, scause, sie, sepc, sstatus};
+}, stval, stvec, scause, sie, sepc, sip};
 use riscv::register::scause::Interrupt;
-use crate::common::{intr_get, intr_off, intr_on};
+use crate::common::{cpuid, intr_off, intr_on};
 use crate::config::*;
 use crate::mm::VirtAddr;
 use crate::syscall::syscall;
-use crate::task::{exit, get_current_task, get_current_tcb_ref, get_current_token, get_current_trap_cx, yield_};
+use crate::task::{current_task, exit, exit_if_killed, get_current_task, get_current_tcb_ref, get_current_switch_token, get_current_trap_cx, fault_in_page, grow_user_stack, guard_page_slot, yield_, STACK_OVERFLOW_XCODE};
 #[cfg(feature = "sbrk_lazy_alloc")]
 use crate::task::lazy_alloc_page;
+use crate::println;
+use crate::profiler;
+use crate::sbi::shutdown;
+use crate::smp::{boot_stack_guard_hart, stop_requested};
 use crate::timer::set_next_trigger;
 
 pub mod context;
@@ -21,33 +27,73 @@ global_asm!(include_str!("trap.S"));
 extern "C" {
   fn __alltraps();
   fn __restore();
+  fn __kerneltrap();
 }
 
+#[repr(C, align(4096))]
+#[derive(Clone, Copy)]
+struct KernelTrapStack([u8; KERNEL_TRAP_STACK_SIZE]);
+
+// kernel traps never run on the faulting stack
+static mut KERNEL_TRAP_STACKS: [KernelTrapStack; MAX_CPU_NUM] =
+  [KernelTrapStack([0; KERNEL_TRAP_STACK_SIZE]); MAX_CPU_NUM];
+// harts handling a trap from kernel
+static IN_KERNEL_TRAP: AtomicUsize = AtomicUsize::new(0);
+const SSTATUS_SPIE: usize = 1 << 5;
+
 pub fn init() {
-  set_user_trap_entry();
+  set_kernel_trap_entry();
+  // IPIs wake idle harts
+  unsafe {
+    sie::set_ssoft();
+  }
+}
+
+/// `[bottom, top)` of trap stack of `hartid`.
+fn trap_stack_position(hartid: usize) -> (usize, usize) {
+  let bottom = unsafe { addr_of!(KERNEL_TRAP_STACKS[hartid]) as usize };
+  (bottom, bottom + KERNEL_TRAP_STACK_SIZE)
+}
+
+/// `[bottom, top)` of the trap stack holding `addr`.
+pub fn trap_stack_bounds(addr: usize) -> Option<(usize, usize)> {
+  (0..MAX_CPU_NUM)
+    .map(trap_stack_position)
+    .find(|&(bottom, top)| (bottom..top).contains(&addr))
 }
 
 pub fn set_kernel_trap_entry() {
+  let (_, trap_stack_top) = trap_stack_position(cpuid());
   unsafe {
-    stvec::write(trap_from_kernel as usize, TrapMode::Direct);
+    stvec::write(__kerneltrap as usize, TrapMode::Direct);
+    // user trap entry sets sscratch again before returning to user
+    asm!("csrw sscratch, {}", in(reg) trap_stack_top);
   }
 }
 
-pub fn set_user_trap_entry() {
-  unsafe {
-    stvec::write(TRAMPOLINE, TrapMode::Direct);
+/// Let timer interrupts sample kernel while profiling, CSRs of current
+/// trap must have been read.
+fn allow_kernel_interrupts() {
+  if profiler::is_running() {
+    intr_on();
   }
 }
 
-pub fn enable_timer_interrupt() {
+pub fn set_user_trap_entry() {
   unsafe {
-    sie::set_stimer();
+    stvec::write(TRAMPOLINE, TrapMode::Direct);
   }
 }
 
-pub fn disable_timer_interrupt() {
+/// Timer interrupts only sample for the profiler, turned on and off for
+/// current hart by `trap_return` alone.
+fn set_timer_interrupt(enabled: bool) {
   unsafe {
-    sie::clear_stimer();
+    if enabled {
+      sie::set_stimer();
+    } else {
+      sie::clear_stimer();
+    }
   }
 }
 
@@ -57,6 +103,7 @@ pub fn trap_handler() -> ! {
   set_kernel_trap_entry();
   let scause = scause::read();
   let stval = stval::read();
+  allow_kernel_interrupts();
   let mut cx = get_current_trap_cx();
   match scause.cause() {
     Trap::Exception(Exception::UserEnvCall) => {
@@ -72,19 +119,42 @@ pub fn trap_handler() -> ! {
     Trap::Exception(Exception::StoreFault)
     | Trap::Exception(Exception::StorePageFault)
     | Trap::Exception(Exception::LoadFault)
-    | Trap::Exception(Exception::LoadPageFault) => {
+    | Trap::Exception(Exception::LoadPageFault)
+    | Trap::Exception(Exception::InstructionPageFault) => {
       let tcb = get_current_tcb_ref();
       let tcb_inner = tcb.inner_borrow_ptr();
-      let ok = if stval >= tcb_inner.heap_bottom && stval < tcb_inner.program_brk {
+      let vpn = VirtAddr::from(stval).floor();
+      let ok = if tcb_inner.memory_set.is_swapped(vpn) || tcb_inner.memory_set.is_unloaded(vpn) {
+        // page was swapped out or elf page is not loaded yet
+        if !fault_in_page(vpn) {
+          debug!("[kernel] Out of memory in application, bad addr = {:#x}, kernel killed it.", stval);
+          exit(-2);
+        }
+        true
+      } else if tcb_inner.memory_set.is_stack_guard(vpn) {
+        // reported regardless of log level
+        println!("[kernel] Stack overflow in application, bad addr = {:#x}, bad instruction = {:#x}, kernel killed it.", stval, cx.sepc);
+        exit(STACK_OVERFLOW_XCODE);
+      } else if tcb_inner.memory_set.is_stack_growth(vpn) {
+        if !grow_user_stack(vpn) {
+          debug!("[kernel] Out of memory in application, bad addr = {:#x}, kernel killed it.", stval);
+          exit(-2);
+        }
+        true
+      } else if stval >= tcb_inner.heap_bottom && stval < tcb_inner.program_brk {
         // lazy allocation for sbrk()
         #[cfg(feature = "sbrk_lazy_alloc")] {
-          lazy_alloc_page(stval.into())
+          if !lazy_alloc_page(stval.into()) {
+            debug!("[kernel] Out of memory in application, bad addr = {:#x}, kernel killed it.", stval);
+            exit(-2);
+          }
+          true
         }
         #[cfg(not(feature = "sbrk_lazy_alloc"))] {
           false
         }
       } else {
-        let to_match = tcb_inner.memory_set.translate(VirtAddr::from(stval).floor());
+        let to_match = tcb_inner.memory_set.translate(vpn);
         match to_match {
           Some(pte) if pte.is_valid() && pte.is_readable() && pte.is_cow_page() => {
             // copy on write
@@ -95,7 +165,10 @@ pub fn trap_handler() -> ! {
           }
         }
       };
-      if !ok {
+      if ok {
+        // invalid entry may be cached
+        tcb_inner.memory_set.flush_tlb(Some(vpn));
+      } else {
         debug!("[kernel] PageFault in application, bad addr = {:#x}, bad instruction = {:#x}, kernel killed it.", stval, cx.sepc);
         exit(-2);
       }
@@ -105,14 +178,26 @@ pub fn trap_handler() -> ! {
       exit(-3);
     }
     Trap::Interrupt(Interrupt::SupervisorTimer) => {
+      profiler::sample(cx.sepc, true);
       set_next_trigger();
       yield_();
     }
+    Trap::Interrupt(Interrupt::SupervisorSoft) => {
+      // IPI from add_task(), nothing to do for a busy hart
+      unsafe {
+        sip::clear_ssoft();
+      }
+      // hart stops in scheduler
+      if stop_requested() {
+        yield_();
+      }
+    }
     _ => {
       debug!("Unsupported trap {:?}, stval = {:#x}", scause.cause(), stval);
       exit(-1);
     }
   }
+  exit_if_killed();
   trap_return()
 }
 
@@ -121,30 +206,63 @@ pub fn fork_ret() -> ! {
   let cur_task = get_current_task();
   cur_task.unlock();
   drop(cur_task);
+  exit_if_killed();
   trap_return()
 }
 
 #[no_mangle]
 pub fn trap_return() -> ! {
+  // no kernel interrupt from here on, user mode takes timer interrupts
+  // regardless of sstatus.SIE, only to be sampled while profiling
   intr_off();
+  set_timer_interrupt(profiler::is_running());
   set_user_trap_entry();
   let trap_cx_ptr_for_va = TRAP_CONTEXT;
-  let user_satp = get_current_token();
+  let user_satp = get_current_switch_token();
   let restore_va = TRAMPOLINE + (__restore as usize - __alltraps as usize);
   let restore_fn =
     unsafe { core::mem::transmute::<_, extern "C" fn(usize, usize) -> !>(restore_va) };
   restore_fn(trap_cx_ptr_for_va, user_satp)
 }
 
+/// Entered from `__kerneltrap` on trap stack of current hart with
+/// registers at the time of trap. Only interrupts return, exceptions are
+/// fatal.
 #[no_mangle]
-pub fn trap_from_kernel() -> ! {
+pub fn trap_from_kernel(regs: &[usize; 32]) {
   let sepc = sepc::read();
-  let sstatus = sstatus::read();
   let scause = scause::read();
+  let stval = stval::read();
+  let hartid = cpuid();
+  if IN_KERNEL_TRAP.fetch_or(1 << hartid, Ordering::AcqRel) & 1 << hartid != 0 {
+    // reporting the first trap went wrong, report this one as is
+    println!(
+      "[kernel] {:?} while handling a kernel trap, hart {}, sepc {:#x}, stval {:#x}",
+      scause.cause(), hartid, sepc, stval,
+    );
+    shutdown(true);
+  }
 
-  if intr_get() != false {
-    panic!("kerneltrap: interrupts enabled");
+  match scause.cause() {
+    // only taken while profiling
+    Trap::Interrupt(Interrupt::SupervisorTimer) => {
+      profiler::sample(sepc, false);
+      set_next_trigger();
+      IN_KERNEL_TRAP.fetch_and(!(1 << hartid), Ordering::AcqRel);
+      return;
+    }
+    Trap::Interrupt(Interrupt::SupervisorSoft) => {
+      // leave the IPI pending for whoever waits for it, and return with
+      // interrupts off until next trap from user
+      unsafe {
+        asm!("csrc sstatus, {}", in(reg) SSTATUS_SPIE);
+      }
+      IN_KERNEL_TRAP.fetch_and(!(1 << hartid), Ordering::AcqRel);
+      return;
+    }
+    _ => {}
   }
+  let pid = current_task().map(|task| task.get_pid());
 
   match scause.cause() {
     Trap::Exception(Exception::UserEnvCall) => {
@@ -154,24 +272,33 @@ pub fn trap_from_kernel() -> ! {
     Trap::Exception(Exception::StoreFault)
     | Trap::Exception(Exception::StorePageFault)
     | Trap::Exception(Exception::LoadFault)
-    | Trap::Exception(Exception::LoadPageFault) => {
-      panic!("a page fault from kernel!");
+    | Trap::Exception(Exception::LoadPageFault)
+    | Trap::Exception(Exception::InstructionPageFault) => {
+      if let Some(slot) = guard_page_slot(stval) {
+        panic!(
+          "kernel stack overflow of slot {}, hart {}, pid {:?}, sepc {:#x}, sp {:#x}, stval {:#x}",
+          slot, hartid, pid, sepc, regs[2], stval,
+        );
+      }
+      if let Some(boot_hart) = boot_stack_guard_hart(stval) {
+        panic!(
+          "boot stack overflow of hart {}, hart {}, pid {:?}, sepc {:#x}, sp {:#x}, stval {:#x}",
+          boot_hart, hartid, pid, sepc, regs[2], stval,
+        );
+      }
+      panic!(
+        "a page fault from kernel! hart {}, pid {:?}, sepc {:#x}, stval {:#x}",
+        hartid, pid, sepc, stval,
+      );
     }
     Trap::Exception(Exception::IllegalInstruction) => {
-      panic!("a illegal instruction from kernel!");
+      panic!("a illegal instruction from kernel! hart {}, pid {:?}, sepc {:#x}", hartid, pid, sepc);
     }
-    Trap::Interrupt(Interrupt::SupervisorTimer) => {
-      set_next_trigger();
-      yield_();
+    _ => {
+      panic!(
+        "a trap from kernel! {:?}, hart {}, pid {:?}, sepc {:#x}, stval {:#x}",
+        scause.cause(), hartid, pid, sepc, stval,
+      );
     }
-    _ => {}
-  }
-
-  sepc::write(sepc);
-  unsafe {
-    asm!(
-    "csrw sstatus, {}", in(reg) sstatus.bits(),
-    );
   }
-  panic!("a trap from kernel!");
 }
@@ -12,4 +12,7 @@ extern "C" {
   pub fn ebss();
   pub fn ekernel();
   pub fn strampoline();
+  pub fn hart_release();
+  pub fn _park_hart();
+  pub fn _secondary_start();
 }
@@ -0,0 +1,25 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{syslog_read, write};
+
+const STDOUT: usize = 1;
+// same as kernel log ring
+const LOG_BUF_SIZE: usize = 1 << 14;
+
+static mut LOG_BUF: [u8; LOG_BUF_SIZE] = [0; LOG_BUF_SIZE];
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let buf = unsafe { &mut *core::ptr::addr_of_mut!(LOG_BUF) };
+    let len = syslog_read(buf);
+    if len < 0 {
+        println!("dmesg: failed to read kernel log");
+        return -1;
+    }
+    write(STDOUT, &buf[..len as usize]);
+    0
+}
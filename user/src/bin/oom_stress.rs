@@ -0,0 +1,68 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{exit, fork, sbrk, sleep, wait};
+
+const PAGE_SIZE: usize = 0x1000;
+// each child holds 1 MiB so that 128 MiB runs out quickly
+const CHILD_PAGES: usize = 256;
+const MAX_CHILD: usize = 1000;
+const HOLD_MS: usize = 3000;
+
+fn hog() -> ! {
+    let base = sbrk((CHILD_PAGES * PAGE_SIZE) as i32);
+    if base == -1 {
+        exit(1);
+    }
+    // touch every page, may be killed here when frames run out
+    for i in 0..CHILD_PAGES {
+        unsafe {
+            ((base as usize + i * PAGE_SIZE) as *mut u8).write_volatile(1);
+        }
+    }
+    sleep(HOLD_MS);
+    exit(0);
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let mut forked = 0;
+    let mut fork_err = 0;
+    for _ in 0..MAX_CHILD {
+        let pid = fork();
+        if pid == 0 {
+            hog();
+        }
+        if pid < 0 {
+            fork_err = pid;
+            break;
+        }
+        forked += 1;
+    }
+    println!("forked {} children, fork error = {}", forked, fork_err);
+
+    let mut exit_code: i32 = 0;
+    let mut killed = 0;
+    for _ in 0..forked {
+        assert!(wait(&mut exit_code) > 0);
+        if exit_code != 0 {
+            killed += 1;
+        }
+    }
+    assert!(wait(&mut exit_code) < 0);
+    println!("{} children did not exit normally", killed);
+
+    // all memory should be back now
+    let pid = fork();
+    if pid == 0 {
+        exit(0);
+    }
+    assert!(pid > 0);
+    assert_eq!(wait(&mut exit_code), pid);
+    assert_eq!(exit_code, 0);
+    println!("oom_stress passed!");
+    0
+}
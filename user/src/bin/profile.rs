@@ -0,0 +1,32 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+#[macro_use]
+extern crate user_lib;
+
+use alloc::vec::Vec;
+use user_lib::{args, execvp, fork, profile_dump, profile_start, profile_stop, waitpid};
+
+/// `profile <prog> [args...]`, samples the whole system while `prog` runs.
+#[no_mangle]
+pub fn main() -> i32 {
+    let args: Vec<&str> = args().skip(1).collect();
+    if args.is_empty() {
+        println!("Usage: profile <prog> [args...]");
+        return -1;
+    }
+    profile_start();
+    let pid = fork();
+    if pid == 0 {
+        execvp(&args);
+        println!("profile: cannot execute {}", args[0]);
+        return -4;
+    }
+    let mut exit_code: i32 = 0;
+    waitpid(pid as usize, &mut exit_code);
+    profile_stop();
+    profile_dump();
+    exit_code
+}
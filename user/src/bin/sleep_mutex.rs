@@ -0,0 +1,42 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{capture_start, exit, fork, kill, waitpid, yield_};
+
+// children write captured output at the same time, so they sleep on the
+// capture lock of kernel, and half of them are killed meanwhile
+const WRITERS: usize = 8;
+const LINES: usize = 50;
+const KILLED_XCODE: i32 = -9;
+
+#[no_mangle]
+pub fn main() -> i32 {
+    capture_start();
+    let mut pids = [0; WRITERS];
+    for (i, pid) in pids.iter_mut().enumerate() {
+        *pid = fork();
+        if *pid == 0 {
+            for line in 0..LINES {
+                println!("writer {} line {}", i, line);
+            }
+            exit(0);
+        }
+        assert!(*pid > 0);
+    }
+    yield_();
+    for pid in pids.iter().step_by(2) {
+        assert_eq!(kill(*pid as usize), 0);
+    }
+    for (i, pid) in pids.iter().enumerate() {
+        let mut exit_code = 0;
+        assert_eq!(waitpid(*pid as usize, &mut exit_code), *pid);
+        // a killed writer may have finished already
+        let killed = i % 2 == 0;
+        assert!(exit_code == 0 || (killed && exit_code == KILLED_XCODE));
+    }
+    println!("sleep_mutex passed!");
+    0
+}
@@ -0,0 +1,36 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+#[macro_use]
+extern crate user_lib;
+
+use alloc::vec::Vec;
+use user_lib::{args, execvp, fork, set_trace, waitpid, TRACE_CONSOLE, TRACE_LOG_RING};
+
+/// `strace [-r] <prog> [args...]`, traces to the kernel log ring with `-r`.
+#[no_mangle]
+pub fn main() -> i32 {
+    let mut args = args().skip(1).peekable();
+    let mode = if args.next_if_eq(&"-r").is_some() {
+        TRACE_LOG_RING
+    } else {
+        TRACE_CONSOLE
+    };
+    let args: Vec<&str> = args.collect();
+    if args.is_empty() {
+        println!("Usage: strace [-r] <prog> [args...]");
+        return -1;
+    }
+    let pid = fork();
+    if pid == 0 {
+        set_trace(mode);
+        execvp(&args);
+        println!("strace: cannot execute {}", args[0]);
+        return -4;
+    }
+    let mut exit_code: i32 = 0;
+    waitpid(pid as usize, &mut exit_code);
+    exit_code
+}
@@ -14,8 +14,9 @@ const DL: u8 = 0x7fu8;
 const BS: u8 = 0x08u8;
 
 use alloc::string::String;
+use alloc::vec::Vec;
 use user_lib::console::getchar;
-use user_lib::{exec, exit, fork, waitpid};
+use user_lib::{execvp, exit, fork, set_log_level, waitpid};
 
 #[no_mangle]
 pub fn main() -> i32 {
@@ -27,13 +28,19 @@ pub fn main() -> i32 {
         match c {
             LF | CR => {
                 println!("");
-                if !line.is_empty() {
-                    line.push('\0');
+                if let Some(level) = line.strip_prefix("loglevel ") {
+                    // builtin, children of the shell may not change it
+                    match level.trim().parse() {
+                        Ok(level) if set_log_level(level) == 0 => {}
+                        _ => println!("Usage: loglevel <0-5>"),
+                    }
+                } else if !line.trim().is_empty() {
+                    let args: Vec<&str> = line.split_whitespace().collect();
                     let pid = fork();
                     if pid == 0 {
                         // child process
                         println!("fork ok");
-                        if exec(line.as_str()) == -1 {
+                        if execvp(&args) < 0 {
                             println!("Error when executing!");
                             return -4;
                         }
@@ -44,8 +51,8 @@ pub fn main() -> i32 {
                         assert_eq!(pid, exit_pid);
                         println!("Shell: Process {} exited with code {}", pid, exit_code);
                     }
-                    line.clear();
                 }
+                line.clear();
                 print!(">> ");
             }
             BS | DL => {
@@ -1,115 +1,228 @@
 #![no_std]
 #![no_main]
 
+extern crate alloc;
+
 #[macro_use]
 extern crate user_lib;
 
-// not in SUCC_TESTS & FAIL_TESTS
-// count_lines, infloop, user_shell, usertests
-
-// item of TESTS : app_name(argv_0), argv_1, argv_2, argv_3, exit_code
-static SUCC_TESTS: &[(&str, &str, &str, &str, i32)] = &[
-    ("exit\0", "\0", "\0", "\0", 0),
-    ("fantastic_text\0", "\0", "\0", "\0", 0),
-    ("forktest_simple\0", "\0", "\0", "\0", 0),
-    ("forktest\0", "\0", "\0", "\0", 0),
-    ("forktest2\0", "\0", "\0", "\0", 0),
-    ("forktree\0", "\0", "\0", "\0", 0),
-    ("hello_world\0", "\0", "\0", "\0", 0),
-    ("matrix\0", "\0", "\0", "\0", 0),
-    ("sleep_simple\0", "\0", "\0", "\0", 0),
-    ("sleep\0", "\0", "\0", "\0", 0),
-    ("yield\0", "\0", "\0", "\0", 0),
-];
-
-static FAIL_TESTS: &[(&str, &str, &str, &str, i32)] = &[
-    ("stack_overflow\0", "\0", "\0", "\0", -2),
-    ("sbrk_test\0", "\0", "\0", "\0", -2),
-];
-
-use user_lib::{exec, fork, waitpid};
-
-fn run_tests(tests: &[(&str, &str, &str, &str, i32)]) -> i32 {
-    let mut pass_num = 0;
-    let mut arr: [*const u8; 4] = [
-        core::ptr::null::<u8>(),
-        core::ptr::null::<u8>(),
-        core::ptr::null::<u8>(),
-        core::ptr::null::<u8>(),
-    ];
-
-    for test in tests {
-        println!("Usertests: Running {}", test.0);
-        arr[0] = test.0.as_ptr();
-        if test.1 != "\0" {
-            arr[1] = test.1.as_ptr();
-            arr[2] = core::ptr::null::<u8>();
-            arr[3] = core::ptr::null::<u8>();
-            if test.2 != "\0" {
-                arr[2] = test.2.as_ptr();
-                arr[3] = core::ptr::null::<u8>();
-                if test.3 != "\0" {
-                    arr[3] = test.3.as_ptr();
-                } else {
-                    arr[3] = core::ptr::null::<u8>();
-                }
-            } else {
-                arr[2] = core::ptr::null::<u8>();
-                arr[3] = core::ptr::null::<u8>();
-            }
-        } else {
-            arr[1] = core::ptr::null::<u8>();
-            arr[2] = core::ptr::null::<u8>();
-            arr[3] = core::ptr::null::<u8>();
+use alloc::vec::Vec;
+use user_lib::{
+    args, capture_read, capture_start, exec, execvp, exit, fork, get_time, kill, syslog_clear,
+    try_waitpid, waitpid, yield_,
+};
+
+// not in the manifest
+// dmesg, forkexec, initproc, profile, user_shell, usertests, usertests-simple, yield_bench
+
+/// Tests to run, the format is described at the top of the manifest.
+static MANIFEST: &str = include_str!("../usertests.manifest");
+
+// exit code of a test whose exec failed
+const EXEC_FAILED: i32 = -127;
+
+struct Test<'a> {
+    argv: Vec<&'a str>,
+    exit_code: i32,
+    timeout_ms: isize,
+    expected: Option<&'a str>,
+}
+
+impl<'a> Test<'a> {
+    /// Parse a manifest line which is neither blank nor a comment.
+    fn parse(line: &'a str) -> Option<Self> {
+        let (command, expected) = match line.split_once("=>") {
+            Some((command, expected)) => (command, Some(expected.trim())),
+            None => (line, None),
+        };
+        let mut fields = command.split_whitespace();
+        let exit_code = fields.next()?.parse().ok()?;
+        let timeout_ms = fields.next()?.parse().ok()?;
+        let argv: Vec<&str> = fields.collect();
+        if argv.is_empty() || expected == Some("") {
+            return None;
         }
+        Some(Self {
+            argv,
+            exit_code,
+            timeout_ms,
+            expected,
+        })
+    }
 
-        let pid = fork();
-        if pid == 0 {
-            exec(test.0);
-            panic!("unreachable!");
-        } else {
-            let mut exit_code: i32 = Default::default();
-            let wait_pid = waitpid(pid as usize, &mut exit_code);
-            assert_eq!(pid, wait_pid);
-            if exit_code == test.4 {
-                // summary apps with  exit_code
-                pass_num = pass_num + 1;
-            }
-            println!(
-                "\x1b[32mUsertests: Test {} in Process {} exited with code {}\x1b[0m",
-                test.0, pid, exit_code
-            );
+    fn name(&self) -> &'a str {
+        self.argv[0]
+    }
+}
+
+/// Whether `expected` appears in output fed piece by piece.
+struct OutputMatcher<'a> {
+    expected: &'a [u8],
+    // end of output seen so far, where a match may start
+    tail: Vec<u8>,
+    found: bool,
+}
+
+impl<'a> OutputMatcher<'a> {
+    fn new(expected: Option<&'a str>) -> Self {
+        Self {
+            expected: expected.unwrap_or("").as_bytes(),
+            tail: Vec::new(),
+            found: expected.is_none(),
+        }
+    }
+
+    fn feed(&mut self, bytes: &[u8]) {
+        if self.found {
+            return;
+        }
+        self.tail.extend_from_slice(bytes);
+        let expected = self.expected;
+        self.found = self.tail.windows(expected.len()).any(|window| window == expected);
+        let keep = expected.len() - 1;
+        if self.tail.len() > keep {
+            self.tail.drain(..self.tail.len() - keep);
         }
     }
-    pass_num
 }
 
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Pass,
+    Fail,
+    Timeout,
+}
+
+impl Outcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Pass => "pass",
+            Outcome::Fail => "fail",
+            Outcome::Timeout => "timeout",
+        }
+    }
+}
+
+/// Hand output captured so far to `f`.
+fn drain_capture(mut f: impl FnMut(&[u8])) {
+    let mut buf = [0u8; 256];
+    loop {
+        let len = capture_read(&mut buf);
+        if len <= 0 {
+            break;
+        }
+        f(&buf[..len as usize]);
+    }
+}
+
+/// Run `test` in a child capturing its stdout, the child and everything it
+/// forked are killed once the timeout passes.
+fn run_test(test: &Test) -> Outcome {
+    // output left by processes of earlier tests
+    drain_capture(|_| {});
+    println!("Usertests: Running {}", test.argv.join(" "));
+    let start = get_time();
+    let pid = fork();
+    if pid == 0 {
+        capture_start();
+        execvp(&test.argv);
+        println!("usertests: cannot execute {}", test.name());
+        exit(EXEC_FAILED);
+    }
+    let mut output = OutputMatcher::new(test.expected);
+    let mut exit_code: i32 = 0;
+    let mut timed_out = false;
+    while try_waitpid(pid as usize, &mut exit_code) == -2 {
+        drain_capture(|bytes| output.feed(bytes));
+        if !timed_out && get_time() - start > test.timeout_ms {
+            kill(pid as usize);
+            timed_out = true;
+        }
+        yield_();
+    }
+    drain_capture(|bytes| output.feed(bytes));
+    let time_ms = get_time() - start;
+
+    let outcome = if timed_out {
+        Outcome::Timeout
+    } else if exit_code == test.exit_code && output.found {
+        Outcome::Pass
+    } else {
+        Outcome::Fail
+    };
+    let output = match test.expected {
+        None => "-",
+        Some(_) if output.found => "ok",
+        Some(_) => "missing",
+    };
+    // key=value pairs, argv takes the rest of the line
+    println!(
+        "[usertests] result={} exit={} expected_exit={} output={} time_ms={} argv={}",
+        outcome.as_str(),
+        exit_code,
+        test.exit_code,
+        output,
+        time_ms,
+        test.argv.join(" "),
+    );
+    outcome
+}
+
+/// Print kernel log of the run, warnings are kept even if console is quiet.
+fn print_kernel_log() {
+    println!("Usertests: kernel log");
+    let pid = fork();
+    if pid == 0 {
+        exec("dmesg\0");
+        panic!("unreachable!");
+    }
+    let mut exit_code: i32 = Default::default();
+    waitpid(pid as usize, &mut exit_code);
+}
+
+/// `usertests [app...]` runs tests of the manifest, only those of given
+/// apps if any. Return 0 if every test passed, as initproc this is the exit
+/// status of QEMU.
 #[no_mangle]
 pub fn main() -> i32 {
-    let succ_num = run_tests(SUCC_TESTS);
-    let err_num = run_tests(FAIL_TESTS);
-    if succ_num == SUCC_TESTS.len() as i32 && err_num == FAIL_TESTS.len() as i32 {
-        println!(
-            "{} of sueecssed apps, {} of failed apps run correctly. \nUsertests passed!",
-            SUCC_TESTS.len(),
-            FAIL_TESTS.len()
-        );
-        return 0;
-    }
-    if succ_num != SUCC_TESTS.len() as i32 {
-        println!(
-            "all successed app_num is  {} , but only  passed {}",
-            SUCC_TESTS.len(),
-            succ_num
-        );
+    // fails unless run as initproc, the log then has earlier records too
+    syslog_clear();
+    let only: Vec<&str> = args().skip(1).collect();
+    let (mut total, mut passed, mut failed, mut timed_out) = (0, 0, 0, 0);
+    for (lineno, line) in MANIFEST.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let test = match Test::parse(line) {
+            Some(test) => test,
+            None => {
+                println!("[usertests] error=malformed line={}", lineno + 1);
+                total += 1;
+                failed += 1;
+                continue;
+            }
+        };
+        if !only.is_empty() && !only.contains(&test.name()) {
+            continue;
+        }
+        total += 1;
+        match run_test(&test) {
+            Outcome::Pass => passed += 1,
+            Outcome::Fail => failed += 1,
+            Outcome::Timeout => timed_out += 1,
+        }
     }
-    if err_num != FAIL_TESTS.len() as i32 {
-        println!(
-            "all failed app_num is  {} , but only  passed {}",
-            FAIL_TESTS.len(),
-            err_num
-        );
+    print_kernel_log();
+    println!(
+        "[usertests] summary total={} passed={} failed={} timed_out={}",
+        total, passed, failed, timed_out
+    );
+    if passed == total {
+        println!("Usertests passed!");
+        0
+    } else {
+        println!("Usertests failed!");
+        -1
     }
-    println!(" Usertests failed!");
-    return -1;
 }
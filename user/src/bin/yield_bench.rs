@@ -0,0 +1,51 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{exit, fork, get_time, wait, yield_};
+
+const PAGE_SIZE: usize = 0x1000;
+const PROCS: usize = 4;
+const ROUNDS: usize = 2000;
+// pages touched between switches, so that flushed TLB entries cost something
+const WORKING_SET: usize = 32;
+
+static mut BUF: [u8; WORKING_SET * PAGE_SIZE] = [0; WORKING_SET * PAGE_SIZE];
+
+fn switch_loop() {
+    for round in 0..ROUNDS {
+        for page in 0..WORKING_SET {
+            unsafe {
+                let p = (core::ptr::addr_of_mut!(BUF) as *mut u8).add(page * PAGE_SIZE);
+                p.write_volatile(round as u8);
+            }
+        }
+        yield_();
+    }
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let start = get_time();
+    for _ in 0..PROCS {
+        if fork() == 0 {
+            switch_loop();
+            exit(0);
+        }
+    }
+    let mut exit_code: i32 = 0;
+    for _ in 0..PROCS {
+        assert!(wait(&mut exit_code) > 0);
+        assert_eq!(exit_code, 0);
+    }
+    let elapsed = get_time() - start;
+    println!(
+        "yield_bench: {} switches in {} ms, {} us per switch",
+        PROCS * ROUNDS,
+        elapsed,
+        elapsed as usize * 1000 / (PROCS * ROUNDS),
+    );
+    0
+}
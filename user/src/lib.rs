@@ -3,11 +3,15 @@
 #![feature(panic_info_message)]
 #![feature(alloc_error_handler)]
 
+extern crate alloc;
+
 #[macro_use]
 pub mod console;
 mod lang_items;
 mod syscall;
 
+use alloc::string::String;
+use alloc::vec::Vec;
 use buddy_system_allocator::LockedHeap;
 use syscall::*;
 
@@ -15,6 +19,9 @@ const USER_HEAP_SIZE: usize = 16384;
 
 static mut HEAP_SPACE: [u8; USER_HEAP_SIZE] = [0; USER_HEAP_SIZE];
 
+// argc and argv given by exec
+static mut ARGS: (usize, usize) = (0, 0);
+
 #[global_allocator]
 static HEAP: LockedHeap = LockedHeap::empty();
 
@@ -25,10 +32,11 @@ pub fn handle_alloc_error(layout: core::alloc::Layout) -> ! {
 
 #[no_mangle]
 #[link_section = ".text.entry"]
-pub extern "C" fn _start() -> ! {
+pub extern "C" fn _start(argc: usize, argv: usize) -> ! {
     unsafe {
         HEAP.lock()
             .init(HEAP_SPACE.as_ptr() as usize, USER_HEAP_SIZE);
+        ARGS = (argc, argv);
     }
     exit(main());
 }
@@ -60,8 +68,41 @@ pub fn getpid() -> isize {
 pub fn fork() -> isize {
     sys_fork()
 }
+/// Arguments given to exec, the first one is the program name.
+pub fn args() -> impl Iterator<Item = &'static str> {
+    let (argc, argv) = unsafe { ARGS };
+    (0..argc).map(move |i| unsafe {
+        let arg = *(argv as *const *const u8).add(i);
+        let len = (0..).take_while(|&j| *arg.add(j) != 0).count();
+        core::str::from_utf8_unchecked(core::slice::from_raw_parts(arg, len))
+    })
+}
+/// Run `path` with itself as the only argument.
 pub fn exec(path: &str) -> isize {
-    sys_exec(path)
+    sys_exec(path, core::ptr::null())
+}
+/// Run `path` with `args`, nul terminated strings followed by a null
+/// pointer. Return `argc` on success.
+pub fn execv(path: &str, args: &[*const u8]) -> isize {
+    sys_exec(path, args.as_ptr())
+}
+/// Run `args[0]` with `args`, which are made nul terminated here.
+/// Return `argc` on success.
+pub fn execvp(args: &[&str]) -> isize {
+    if args.is_empty() {
+        return -1;
+    }
+    let args: Vec<String> = args
+        .iter()
+        .map(|arg| {
+            let mut arg = String::from(*arg);
+            arg.push('\0');
+            arg
+        })
+        .collect();
+    let mut argv: Vec<*const u8> = args.iter().map(|arg| arg.as_ptr()).collect();
+    argv.push(core::ptr::null());
+    execv(args[0].as_str(), &argv)
 }
 pub fn wait(exit_code: &mut i32) -> isize {
     loop {
@@ -86,6 +127,14 @@ pub fn waitpid(pid: usize, exit_code: &mut i32) -> isize {
         }
     }
 }
+/// Same as [`waitpid`] but return -2 at once if `pid` has not exited.
+pub fn try_waitpid(pid: usize, exit_code: &mut i32) -> isize {
+    sys_waitpid(pid as isize, exit_code as *mut _)
+}
+/// Kill child or descendant `pid` and everything it forked.
+pub fn kill(pid: usize) -> isize {
+    sys_kill(pid)
+}
 pub fn sleep(period_ms: usize) {
     let start = sys_get_time();
     while sys_get_time() < start + period_ms as isize {
@@ -97,3 +146,79 @@ pub fn sbrk(size: i32) -> isize {
     sys_sbrk(size)
 }
 
+pub const TRACE_OFF: usize = 0;
+pub const TRACE_CONSOLE: usize = 1;
+pub const TRACE_LOG_RING: usize = 2;
+
+/// Trace syscalls of this process and processes it forks or execs.
+pub fn set_trace(mode: usize) -> isize {
+    sys_set_trace(mode)
+}
+
+const PROFILE_STOP: usize = 0;
+const PROFILE_START: usize = 1;
+const PROFILE_DUMP: usize = 2;
+
+/// Start kernel sampling profiler over, samples taken before are dropped.
+pub fn profile_start() -> isize {
+    sys_profile(PROFILE_START)
+}
+pub fn profile_stop() -> isize {
+    sys_profile(PROFILE_STOP)
+}
+/// Print histogram of samples to console.
+pub fn profile_dump() -> isize {
+    sys_profile(PROFILE_DUMP)
+}
+
+const SYSLOG_ACTION_READ_ALL: usize = 3;
+const SYSLOG_ACTION_READ_CLEAR: usize = 4;
+const SYSLOG_ACTION_CLEAR: usize = 5;
+const SYSLOG_ACTION_CONSOLE_LEVEL: usize = 8;
+const SYSLOG_ACTION_SIZE_BUFFER: usize = 10;
+
+/// Read newest kernel log lines fitting in `buf`, return bytes read.
+pub fn syslog_read(buf: &mut [u8]) -> isize {
+    let len = buf.len();
+    sys_syslog(SYSLOG_ACTION_READ_ALL, buf, len)
+}
+
+/// Same as [`syslog_read`] but also clear the kernel log, only initproc
+/// and the shell are allowed to.
+pub fn syslog_read_clear(buf: &mut [u8]) -> isize {
+    let len = buf.len();
+    sys_syslog(SYSLOG_ACTION_READ_CLEAR, buf, len)
+}
+
+/// Clear the kernel log, only initproc and the shell are allowed to.
+pub fn syslog_clear() -> isize {
+    sys_syslog(SYSLOG_ACTION_CLEAR, &mut [], 0)
+}
+
+/// Size of the kernel log ring in bytes.
+pub fn syslog_size() -> isize {
+    sys_syslog(SYSLOG_ACTION_SIZE_BUFFER, &mut [], 0)
+}
+
+/// Set kernel log level from 0 for off up to 5 for trace, only initproc
+/// and the shell are allowed to.
+pub fn set_log_level(level: usize) -> isize {
+    sys_syslog(SYSLOG_ACTION_CONSOLE_LEVEL, &mut [], level)
+}
+
+const CAPTURE_OFF: usize = 0;
+const CAPTURE_ON: usize = 1;
+const CAPTURE_READ: usize = 2;
+
+/// Also collect stdout of this process and processes it forks or execs.
+pub fn capture_start() -> isize {
+    sys_capture(CAPTURE_ON, &mut [])
+}
+pub fn capture_stop() -> isize {
+    sys_capture(CAPTURE_OFF, &mut [])
+}
+/// Move oldest collected output of every capturing process to `buf`,
+/// the kernel keeps only the newest 4 KiB. Return bytes read.
+pub fn capture_read(buf: &mut [u8]) -> isize {
+    sys_capture(CAPTURE_READ, buf)
+}
@@ -3,13 +3,18 @@ use core::arch::asm;
 const SYSCALL_READ: usize = 63;
 const SYSCALL_WRITE: usize = 64;
 const SYSCALL_EXIT: usize = 93;
+const SYSCALL_SYSLOG: usize = 116;
 const SYSCALL_YIELD: usize = 124;
+const SYSCALL_KILL: usize = 129;
 const SYSCALL_GET_TIME: usize = 169;
 const SYSCALL_GETPID: usize = 172;
 const SYSCALL_SBRK: usize = 214;
 const SYSCALL_FORK: usize = 220;
 const SYSCALL_EXEC: usize = 221;
 const SYSCALL_WAITPID: usize = 260;
+const SYSCALL_SET_TRACE: usize = 114515;
+const SYSCALL_PROFILE: usize = 114516;
+const SYSCALL_CAPTURE: usize = 114517;
 
 fn syscall(id: usize, args: [usize; 3]) -> isize {
     let mut ret: isize;
@@ -41,6 +46,10 @@ pub fn sys_exit(exit_code: i32) -> ! {
     panic!("sys_exit never returns!");
 }
 
+pub fn sys_syslog(action: usize, buf: &mut [u8], len: usize) -> isize {
+    syscall(SYSCALL_SYSLOG, [action, buf.as_mut_ptr() as usize, len])
+}
+
 pub fn sys_yield() -> isize {
     syscall(SYSCALL_YIELD, [0, 0, 0])
 }
@@ -57,8 +66,8 @@ pub fn sys_fork() -> isize {
     syscall(SYSCALL_FORK, [0, 0, 0])
 }
 
-pub fn sys_exec(path: &str) -> isize {
-    syscall(SYSCALL_EXEC, [path.as_ptr() as usize, 0, 0])
+pub fn sys_exec(path: &str, args: *const *const u8) -> isize {
+    syscall(SYSCALL_EXEC, [path.as_ptr() as usize, args as usize, 0])
 }
 
 pub fn sys_waitpid(pid: isize, exit_code: *mut i32) -> isize {
@@ -69,3 +78,19 @@ pub fn sys_sbrk(size: i32) -> isize {
     syscall(SYSCALL_SBRK, [size as usize, 0, 0])
 }
 
+
+pub fn sys_set_trace(mode: usize) -> isize {
+    syscall(SYSCALL_SET_TRACE, [mode, 0, 0])
+}
+
+pub fn sys_profile(cmd: usize) -> isize {
+    syscall(SYSCALL_PROFILE, [cmd, 0, 0])
+}
+
+pub fn sys_kill(pid: usize) -> isize {
+    syscall(SYSCALL_KILL, [pid, 0, 0])
+}
+
+pub fn sys_capture(cmd: usize, buf: &mut [u8]) -> isize {
+    syscall(SYSCALL_CAPTURE, [cmd, buf.as_mut_ptr() as usize, buf.len()])
+}